@@ -0,0 +1,203 @@
+//! CGI/1.1 (RFC 3875), so existing CGI scripts can be served unmodified.
+//!
+//! A directory opts in with `cgi = true` in its `.config`, for itself and
+//! everything under it.  Handlers there are run as CGI scripts: instead of
+//! arguments, they get the request in the standard meta-variables, and they
+//! start their stdout with a header block that ends in an empty line.  A
+//! `Status:` header sets the status, and a `Location:` without one makes it
+//! a 302.  The response streams as soon as the header block is done, so
+//! exiting with anything but 0 cuts it short, and whatever they write to
+//! stderr goes to the log.
+//!
+//! An executable file with more of the path after it handles the rest of the
+//! path, which it gets as `PATH_INFO`.  `.pre_process`, `.post_process` and
+//! `.error` handlers aren't CGI scripts, and keep working as usual.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use http::{
+	header::{
+		HeaderName, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, PROXY_AUTHORIZATION,
+	},
+	request::Parts,
+};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+	process::{ChildStderr, ChildStdin, ChildStdout},
+};
+
+use crate::{decode, log, vhost};
+
+pub const SERVER_SOFTWARE: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+// a header block longer than this is surely not one
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+
+/// Headers from a script, and the status if it picked one.
+pub type HeaderBlock = (Vec<(String, String)>, Option<u16>);
+
+/// Where a connection comes from and goes to.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+	pub remote: SocketAddr,
+	pub local: SocketAddr,
+	pub secure: bool,
+}
+
+/// The meta-variables for a script, given the part of the path after it.
+pub fn env(req: &Parts, peer: &Peer, path_info: &[String]) -> Vec<(String, String)> {
+	let segments = decode::path_segments(req.uri.path()).unwrap_or_default();
+	let script = &segments[..segments.len().saturating_sub(path_info.len())];
+	let header = |name| req.headers.get(name).and_then(|v| v.to_str().ok()).map(String::from);
+	let mut env = vec![
+		("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+		("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string()),
+		("SERVER_PROTOCOL", format!("{:?}", req.version)),
+		(
+			"SERVER_NAME",
+			vhost::request_host(req).unwrap_or_else(|| peer.local.ip().to_string()),
+		),
+		("SERVER_PORT", peer.local.port().to_string()),
+		("REMOTE_ADDR", peer.remote.ip().to_string()),
+		("REMOTE_PORT", peer.remote.port().to_string()),
+		("REQUEST_METHOD", req.method.to_string()),
+		(
+			"REQUEST_URI",
+			req.uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+		),
+		("QUERY_STRING", req.uri.query().unwrap_or("").to_string()),
+		("SCRIPT_NAME", script.iter().map(|s| format!("/{}", s)).collect()),
+		("PATH_INFO", path_info.iter().map(|s| format!("/{}", s)).collect()),
+	]
+		.into_iter()
+		.map(|(k, v)| (k.to_string(), v))
+		.collect::<Vec<_>>();
+	if peer.secure {
+		env.push(("HTTPS".to_string(), "on".to_string()));
+	}
+	if let Some(length) = header(CONTENT_LENGTH) {
+		env.push(("CONTENT_LENGTH".to_string(), length));
+	}
+	if let Some(kind) = header(CONTENT_TYPE) {
+		env.push(("CONTENT_TYPE".to_string(), kind));
+	}
+	// credentials aren't passed on, as the RFC recommends, and neither is
+	// `Proxy`, which would be `HTTP_PROXY` to scripts (httpoxy)
+	let skipped = [
+		CONTENT_LENGTH,
+		CONTENT_TYPE,
+		AUTHORIZATION,
+		PROXY_AUTHORIZATION,
+		HeaderName::from_static("proxy"),
+	];
+	for name in req.headers.keys().filter(|n| !skipped.contains(n)) {
+		let separator = if name == COOKIE { "; " } else { ", " };
+		let value = req.headers
+			.get_all(name)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.collect::<Vec<&str>>()
+			.join(separator);
+		env.push((
+			format!("HTTP_{}", name.as_str().to_ascii_uppercase().replace("-", "_")),
+			value,
+		));
+	}
+	env
+}
+
+/// Replaces `CONTENT_LENGTH` with the length of the body as it is by now,
+/// since pre-processing can change it.
+pub fn set_content_length(env: &mut Vec<(String, String)>, len: u64) {
+	env.retain(|(k, _)| k != "CONTENT_LENGTH");
+	env.push(("CONTENT_LENGTH".to_string(), len.to_string()));
+}
+
+/// Reads the header block a script's output starts with, leaving whatever
+/// came after it in `buffered`.
+pub async fn read_headers(
+	stdout: &mut (impl AsyncRead + Unpin),
+	buffered: &mut Vec<u8>,
+) -> Result<HeaderBlock, String> {
+	let mut lines = Vec::new();
+	let mut start = 0;
+	loop {
+		while let Some(end) = buffered[start..].iter().position(|b| *b == b'\n') {
+			let line = String::from_utf8_lossy(&buffered[start..start + end])
+				.trim_end_matches("\r")
+				.to_string();
+			start += end + 1;
+			if line.is_empty() {
+				buffered.drain(..start);
+				return parse_headers(lines);
+			}
+			lines.push(line);
+		}
+		if buffered.len() > MAX_HEADER_BLOCK {
+			return Err("header block is too long".to_string());
+		}
+		let read = stdout.read_buf(buffered).await.map_err(|e| e.to_string())?;
+		if read == 0 {
+			return Err("output ended before the header block did".to_string());
+		}
+	}
+}
+
+fn parse_headers(lines: Vec<String>) -> Result<HeaderBlock, String> {
+	let mut headers = Vec::new();
+	let mut status = None;
+	for line in lines {
+		let (name, value) = line
+			.split_once(":")
+			.ok_or(format!("{:?} is not a header", line))?;
+		let (name, value) = (name.trim(), value.trim());
+		if name.eq_ignore_ascii_case("status") {
+			status = Some(
+				value
+					.split_whitespace()
+					.next()
+					.and_then(|s| s.parse::<u16>().ok())
+					.filter(|s| (100..600).contains(s))
+					.ok_or(format!("{:?} is not a status", value))?,
+			);
+		} else {
+			headers.push((name.to_string(), value.to_string()));
+		}
+	}
+	if status.is_none() && headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("location")) {
+		status = Some(302);
+	}
+	Ok((headers, status))
+}
+
+/// Sends what a process writes to stderr to the log, a line at a time.
+pub fn log_stderr(stderr: Option<ChildStderr>, origin: PathBuf, prefix: &'static str) {
+	let Some(stderr) = stderr else {
+		return;
+	};
+	tokio::spawn(async move {
+		let mut lines = BufReader::new(stderr).lines();
+		while let Ok(Some(line)) = lines.next_line().await {
+			log!(error prefix; "{}: {}", origin.display(), line);
+		}
+	});
+}
+
+/// Passes the body of a script on to the next process, without its header
+/// block, which only means something at the end of a chain.
+pub fn relay_body(mut stdout: ChildStdout, stdin: Option<ChildStdin>, origin: PathBuf) {
+	let Some(mut stdin) = stdin else {
+		return;
+	};
+	tokio::spawn(async move {
+		let mut buffered = Vec::new();
+		if let Err(e) = read_headers(&mut stdout, &mut buffered).await {
+			log!(error "CGI"; "Bad output from {}: {}", origin.display(), e);
+			return;
+		}
+		// the next process is allowed to stop reading early
+		if stdin.write_all(&buffered).await.is_ok() {
+			let _ = tokio::io::copy(&mut stdout, &mut stdin).await;
+		}
+	});
+}
@@ -0,0 +1,266 @@
+//! `Accept-Encoding` negotiation, and compressing responses on the fly.
+//!
+//! Compression applies to static files and process chains alike, as long as
+//! the `Content-Type` is on the compressible list and the body isn't known to
+//! be too small.  Responses that already have a `Content-Encoding`, like from
+//! an executable that compresses its own output, are left alone.  What's
+//! compressed is another representation, so it gets a weak tag of its own,
+//! with the encoding added to the original one.
+
+use async_compression::{
+	tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+	Level,
+};
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::{
+	body::{Body, Frame},
+	header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH,
+		CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY},
+	Response,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::serve::{Config, ResponseBody};
+
+/// Types that are worth compressing, when none are configured.  An entry
+/// ending in `/*` covers every subtype.
+pub const DEFAULT_TYPES: &[&str] = &[
+	"text/*",
+	"application/json",
+	"application/javascript",
+	"application/xml",
+	"application/manifest+json",
+	"application/wasm",
+	"image/svg+xml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	Brotli,
+	Zstd,
+	Gzip,
+}
+
+impl Encoding {
+	// order is the preference when the client likes several equally
+	pub const ALL: &[Encoding] = &[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Zstd => "zstd",
+			Encoding::Gzip => "gzip",
+		}
+	}
+
+	/// extension of precompressed files in this encoding
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Encoding::Brotli => "br",
+			Encoding::Zstd => "zst",
+			Encoding::Gzip => "gz",
+		}
+	}
+}
+
+/// The entries of an `Accept`-style header with their q-values.  An entry
+/// without one has a q of 1, one that doesn't parse a q of 0.
+pub fn weighted(header: &str) -> impl Iterator<Item = (&str, f32)> {
+	header.split(",").map(|entry| {
+		let mut parts = entry.split(";").map(str::trim);
+		let value = parts.next().unwrap_or("");
+		let q = parts
+			.find_map(|p| p.strip_prefix("q="))
+			.map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+			.unwrap_or(0.0);
+		(value, q)
+	})
+}
+
+// q-value given to an encoding by an Accept-Encoding header
+fn quality(accept: &str, name: &str) -> Option<f32> {
+	let mut wildcard = None;
+	for (coding, q) in weighted(accept) {
+		if coding.eq_ignore_ascii_case(name) {
+			return Some(q);
+		}
+		if coding == "*" {
+			wildcard = Some(q);
+		}
+	}
+	wildcard
+}
+
+/// The best of the given encodings the client will take, if any.
+pub fn negotiate(headers: &HeaderMap, available: &[Encoding]) -> Option<Encoding> {
+	let accept = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+	let mut best: Option<(Encoding, f32)> = None;
+	for encoding in available {
+		let Some(q) = quality(accept, encoding.name()).filter(|q| *q > 0.0) else {
+			continue;
+		};
+		if best.is_none_or(|(_, best_q)| q > best_q) {
+			best = Some((*encoding, q));
+		}
+	}
+	best.map(|(e, _)| e)
+}
+
+/// Whether responses of this `Content-Type` should be compressed.
+pub fn compressible(content_type: &str, types: &[String]) -> bool {
+	let essence = content_type
+		.split(";")
+		.next()
+		.unwrap_or("")
+		.trim()
+		.to_ascii_lowercase();
+	types.iter().any(|t| match t.strip_suffix("/*") {
+		Some(top) => essence.split("/").next() == Some(top),
+		None => essence == *t,
+	})
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+	let varies = headers
+		.get_all(VARY)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(","))
+		.any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"));
+	if !varies {
+		headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+	}
+}
+
+/// Whether a response of this type and length depends on `Accept-Encoding`,
+/// and the encoding it's compressed with for this request, if any.
+pub fn choose(content_type: &str, len: Option<u64>, req: &HeaderMap, config: &Config) -> (bool, Option<Encoding>) {
+	if !config.compress || !compressible(content_type, &config.compress_types) {
+		return (false, None);
+	}
+	// it varies even if it turns out not to be compressed this time
+	if len.is_some_and(|len| len < config.compress_min_size) {
+		return (true, None);
+	}
+	(true, negotiate(req, Encoding::ALL))
+}
+
+/// The tag of a compressed variant, which is another representation than the
+/// one `etag` is for.  Weak, since the compressor can change its output.
+pub fn variant_tag(etag: &str, encoding: Encoding) -> String {
+	let opaque = etag.trim().trim_start_matches("W/").trim_matches('"');
+	format!("W/\"{}-{}\"", opaque, encoding.name())
+}
+
+/// Compresses a response, if the request and configuration allow it.
+pub fn compress(mut resp: Response<ResponseBody>, req: &HeaderMap, config: &Config) -> Response<ResponseBody> {
+	let status = resp.status().as_u16();
+	let headers = resp.headers();
+	if headers.contains_key(CONTENT_ENCODING)
+		|| headers.contains_key(CONTENT_RANGE)
+		|| !(200..300).contains(&status)
+		|| status == 204
+	{
+		return resp;
+	}
+	let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|t| t.to_str().ok()) else {
+		return resp;
+	};
+	// a streamed file only has its length in the header
+	let len = headers
+		.get(CONTENT_LENGTH)
+		.and_then(|l| l.to_str().ok()?.parse::<u64>().ok())
+		.or_else(|| resp.body().size_hint().exact());
+	let (varies, encoding) = choose(content_type, len, req, config);
+	if varies {
+		add_vary(resp.headers_mut());
+	}
+	let Some(encoding) = encoding else {
+		return resp;
+	};
+
+	let (mut parts, body) = resp.into_parts();
+	parts.headers.remove(CONTENT_LENGTH);
+	parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+	// the compressed bytes are a representation of their own
+	if let Some(etag) = parts.headers.get(ETAG).and_then(|e| e.to_str().ok())
+		&& let Ok(tag) = HeaderValue::from_str(&variant_tag(etag, encoding))
+	{
+		parts.headers.insert(ETAG, tag);
+	}
+	let reader = StreamReader::new(
+		BodyStream::new(body).try_filter_map(|f| std::future::ready(Ok(f.into_data().ok())))
+	);
+	let body = match encoding {
+		Encoding::Gzip => encoded(GzipEncoder::new(reader)),
+		// brotli's default is its slowest, too slow to do per request
+		Encoding::Brotli => encoded(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+		Encoding::Zstd => encoded(ZstdEncoder::new(reader)),
+	};
+	Response::from_parts(parts, body)
+}
+
+fn encoded<R: tokio::io::AsyncRead + Send + 'static>(reader: R) -> ResponseBody {
+	StreamBody::new(ReaderStream::new(reader).map_ok(Frame::data)).boxed_unsync()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn accepting(value: &str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+		headers
+	}
+
+	#[test]
+	fn q_values() {
+		let parsed = weighted("gzip, br;q=0.5 , zstd;q=0, x;q=nope, *;q=0.1").collect::<Vec<_>>();
+		assert_eq!(parsed, [("gzip", 1.0), ("br", 0.5), ("zstd", 0.0), ("x", 0.0), ("*", 0.1)]);
+		assert_eq!(weighted("text/html;level=1;q=0.7").collect::<Vec<_>>(), [("text/html", 0.7)]);
+	}
+
+	#[test]
+	fn negotiation() {
+		let all = Encoding::ALL;
+		assert_eq!(negotiate(&HeaderMap::new(), all), None);
+		assert_eq!(negotiate(&accepting("gzip"), all), Some(Encoding::Gzip));
+		// equal preferences go by the server's order
+		assert_eq!(negotiate(&accepting("gzip, br, zstd"), all), Some(Encoding::Brotli));
+		assert_eq!(negotiate(&accepting("gzip;q=1, br;q=0.5"), all), Some(Encoding::Gzip));
+		assert_eq!(negotiate(&accepting("GZIP"), all), Some(Encoding::Gzip));
+		assert_eq!(negotiate(&accepting("br, gzip"), &[Encoding::Gzip]), Some(Encoding::Gzip));
+	}
+
+	#[test]
+	fn refused() {
+		let all = Encoding::ALL;
+		assert_eq!(negotiate(&accepting("gzip;q=0"), all), None);
+		assert_eq!(negotiate(&accepting("identity"), all), None);
+		assert_eq!(negotiate(&accepting("identity;q=0"), all), None);
+		// a wildcard covers whatever isn't named
+		assert_eq!(negotiate(&accepting("*"), all), Some(Encoding::Brotli));
+		assert_eq!(negotiate(&accepting("*;q=0"), all), None);
+		assert_eq!(negotiate(&accepting("*, br;q=0, zstd;q=0"), all), Some(Encoding::Gzip));
+		assert_eq!(negotiate(&accepting("gzip;q=0, *;q=0.5"), &[Encoding::Gzip]), None);
+	}
+
+	#[test]
+	fn variant_tags() {
+		assert_eq!(variant_tag("\"abc\"", Encoding::Gzip), "W/\"abc-gzip\"");
+		assert_eq!(variant_tag("W/\"abc\"", Encoding::Brotli), "W/\"abc-br\"");
+		assert_ne!(variant_tag("\"abc\"", Encoding::Gzip), variant_tag("\"abc\"", Encoding::Zstd));
+	}
+
+	#[test]
+	fn compressible_types() {
+		let types = ["text/*", "application/json"].map(String::from);
+		assert!(compressible("text/html; charset=utf-8", &types));
+		assert!(compressible("Application/JSON", &types));
+		assert!(!compressible("application/jsonx", &types));
+		assert!(!compressible("image/png", &types));
+		assert!(!compressible("textual/plain", &types));
+	}
+}
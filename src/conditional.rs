@@ -0,0 +1,138 @@
+//! Validators and conditional requests (RFC 9110, section 13).
+//!
+//! Static files get an `ETag` and a `Last-Modified` from their metadata.
+//! Weak tags come straight from the size and modification time.  Strong tags
+//! are a SHA-256 of the contents, so they stay the same from one build to the
+//! next, and are cached per path until the file changes.
+
+use std::{
+	collections::HashMap,
+	fs::Metadata,
+	path::{Path, PathBuf},
+	sync::{LazyLock, Mutex},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use ring::digest::{Context, SHA256};
+use tokio::{fs::File, io::AsyncReadExt};
+
+// headers a 304 is allowed to carry over from the full response
+pub const NOT_MODIFIED_HEADERS: &[&str] = &[
+	"cache-control", "content-location", "date", "etag", "expires", "vary", "last-modified",
+];
+
+pub fn http_date(time: SystemTime) -> String {
+	DateTime::<Utc>::from(time)
+		.format("%a, %d %b %Y %H:%M:%S GMT")
+		.to_string()
+}
+
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+	DateTime::parse_from_rfc2822(date.trim())
+		.ok()
+		.map(SystemTime::from)
+}
+
+// http dates only go down to the second
+fn truncate(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[derive(Debug)]
+struct Cached {
+	modified: Option<SystemTime>,
+	len: u64,
+	tag: String,
+}
+
+static HASHES: LazyLock<Mutex<HashMap<PathBuf, Cached>>> = LazyLock::new(Default::default);
+
+/// Entity tag for a static file.  Only needs to read the file for a strong
+/// tag, and only when it changed since the last time.
+pub async fn etag(path: &Path, file: &mut File, metadata: &Metadata, strong: bool) -> Option<String> {
+	let modified = metadata.modified().ok();
+	if !strong {
+		let since = modified?.duration_since(UNIX_EPOCH).ok()?;
+		return Some(format!(
+			"W/\"{:x}.{:x}-{:x}\"",
+			since.as_secs(),
+			since.subsec_nanos(),
+			metadata.len()
+		));
+	}
+	if let Some(cached) = HASHES.lock().unwrap().get(path)
+		&& cached.modified == modified
+		&& cached.len == metadata.len()
+	{
+		return Some(cached.tag.clone());
+	}
+	let mut hasher = Context::new(&SHA256);
+	let mut buf = vec![0; 64 * 1024];
+	loop {
+		let read = file.read(&mut buf).await.ok()?;
+		if read == 0 {
+			break;
+		}
+		hasher.update(&buf[..read]);
+	}
+	let hash = hasher.finish();
+	// half of it is plenty to tell versions of a file apart
+	let hex = hash.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+	let tag = format!("\"{}\"", hex);
+	HASHES.lock().unwrap().insert(
+		path.to_path_buf(),
+		Cached {
+			modified,
+			len: metadata.len(),
+			tag: tag.clone(),
+		},
+	);
+	Some(tag)
+}
+
+fn opaque(tag: &str) -> &str {
+	tag.trim().trim_start_matches("W/")
+}
+
+/// Strong comparison, neither side can be weak.
+pub fn strong_match(a: &str, b: &str) -> bool {
+	!a.trim().starts_with("W/") && !b.trim().starts_with("W/") && a.trim() == b.trim()
+}
+
+fn weak_match(a: &str, b: &str) -> bool {
+	opaque(a) == opaque(b)
+}
+
+/// Whether a GET or HEAD can be answered with 304 Not Modified.
+/// `If-None-Match` takes priority, `If-Modified-Since` only counts without it.
+pub fn not_modified(headers: &HeaderMap, etag: Option<&str>, modified: Option<SystemTime>) -> bool {
+	if let Some(none_match) = headers.get(IF_NONE_MATCH) {
+		let Ok(none_match) = none_match.to_str() else {
+			return false;
+		};
+		return none_match.trim() == "*" && etag.is_some()
+			|| etag.is_some_and(|etag| none_match.split(",").any(|t| weak_match(t, etag)));
+	}
+	let since = headers
+		.get(IF_MODIFIED_SINCE)
+		.and_then(|s| s.to_str().ok())
+		.and_then(parse_http_date);
+	match (since, modified) {
+		(Some(since), Some(modified)) => truncate(modified) <= truncate(since),
+		_ => false,
+	}
+}
+
+/// `If-Range` is either a date, which has to match exactly, or an entity tag,
+/// which has to match strongly.
+pub fn if_range_matches(validator: &str, etag: Option<&str>, modified: Option<SystemTime>) -> bool {
+	if validator.trim().starts_with("\"") || validator.trim().starts_with("W/") {
+		return etag.is_some_and(|etag| strong_match(validator, etag));
+	}
+	match (parse_http_date(validator), modified) {
+		(Some(date), Some(modified)) => truncate(date) == truncate(modified),
+		_ => false,
+	}
+}
@@ -0,0 +1,170 @@
+//! Percent-decoding and normalizing request targets, before any routing.
+//!
+//! The path is split on `/`, with empty segments from duplicate slashes
+//! dropped, and each segment decoded on its own.  Decoded segments have to be
+//! UTF-8, and can't have a `/` or a NUL in them, so `%2F` can't add a level
+//! and nothing is cut short when it's passed to an executable.  Then `.` and
+//! `..` segments are resolved, whether they were encoded or not, without ever
+//! going above the top.  What's left is what hidden entries are checked
+//! against, so encoding a leading `.` or `&` doesn't get around them.
+//!
+//! Query parameters are decoded the same way, with `+` as a space, into
+//! `name=value`.  Anything that can't be decoded is a 400.
+
+use std::fmt::Write;
+
+fn hex(b: u8) -> Option<u8> {
+	match b {
+		b'0'..=b'9' => Some(b - b'0'),
+		b'a'..=b'f' => Some(b - b'a' + 10),
+		b'A'..=b'F' => Some(b - b'A' + 10),
+		_ => None,
+	}
+}
+
+/// Decodes `%XX` escapes, and `+` too if it stands for a space.  `None` if
+/// an escape is malformed, or the result isn't UTF-8 or has a NUL.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+	let bytes = s.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'%' => {
+				let high = hex(*bytes.get(i + 1)?)?;
+				let low = hex(*bytes.get(i + 2)?)?;
+				decoded.push(high << 4 | low);
+				i += 3;
+			}
+			b'+' if plus_as_space => {
+				decoded.push(b' ');
+				i += 1;
+			}
+			b => {
+				decoded.push(b);
+				i += 1;
+			}
+		}
+	}
+	if decoded.contains(&0) {
+		return None;
+	}
+	String::from_utf8(decoded).ok()
+}
+
+/// Encodes everything but unreserved characters, for one path segment.
+pub fn percent_encode(s: &str) -> String {
+	let mut encoded = String::with_capacity(s.len());
+	for b in s.bytes() {
+		if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+			encoded.push(b as char);
+		} else {
+			let _ = write!(encoded, "%{:02X}", b);
+		}
+	}
+	encoded
+}
+
+/// The decoded segments of a path, with dot segments resolved.
+pub fn path_segments(path: &str) -> Option<Vec<String>> {
+	let mut segments: Vec<String> = Vec::new();
+	for raw in path.split("/").filter(|s| !s.is_empty()) {
+		let segment = percent_decode(raw, false)?;
+		// an encoded slash would be a segment that's secretly two
+		if segment.contains("/") {
+			return None;
+		}
+		match segment.as_str() {
+			"." => {}
+			".." => {
+				segments.pop();
+			}
+			_ => segments.push(segment),
+		}
+	}
+	Some(segments)
+}
+
+/// The decoded `name=value` pairs of a query string.  A pair without a `=`
+/// stays that way.
+pub fn query_params(query: &str) -> Option<Vec<String>> {
+	query
+		.split("&")
+		.filter(|s| !s.is_empty())
+		.map(|pair| match pair.split_once("=") {
+			Some((name, value)) => Some(format!(
+				"{}={}",
+				percent_decode(name, true)?,
+				percent_decode(value, true)?
+			)),
+			None => percent_decode(pair, true),
+		})
+		// an empty one would look like the end of the section to executables
+		.filter(|p| p.as_ref().is_none_or(|p| !p.is_empty()))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn owned(items: &[&str]) -> Option<Vec<String>> {
+		Some(items.iter().map(|s| s.to_string()).collect())
+	}
+
+	#[test]
+	fn dot_segments() {
+		assert_eq!(path_segments("/a/./b/../c"), owned(&["a", "c"]));
+		assert_eq!(path_segments("/a/%2e/b/%2E%2e/c"), owned(&["a", "c"]));
+		assert_eq!(path_segments("/../../a"), owned(&["a"]));
+		assert_eq!(path_segments("/%2e%2e/%2e%2e/a"), owned(&["a"]));
+	}
+
+	#[test]
+	fn duplicate_slashes() {
+		assert_eq!(path_segments("//a///b/"), owned(&["a", "b"]));
+		assert_eq!(path_segments("/"), owned(&[]));
+	}
+
+	#[test]
+	fn encoded_slash() {
+		assert_eq!(path_segments("/a%2F..%2Fb"), None);
+		assert_eq!(path_segments("/a%2fb"), None);
+	}
+
+	#[test]
+	fn hidden_stays_visible() {
+		assert_eq!(path_segments("/%2esecret"), owned(&[".secret"]));
+		assert_eq!(path_segments("/%26secret"), owned(&["&secret"]));
+		assert_eq!(path_segments("/sub/%2e%2e/%2esecret"), owned(&[".secret"]));
+	}
+
+	#[test]
+	fn bad_segments() {
+		assert_eq!(path_segments("/caf%C3%A9"), owned(&["café"]));
+		assert_eq!(path_segments("/%FF"), None);
+		assert_eq!(path_segments("/a%00b"), None);
+		assert_eq!(path_segments("/a%2"), None);
+		assert_eq!(path_segments("/a%zz"), None);
+		assert_eq!(path_segments("/a+b"), owned(&["a+b"]));
+	}
+
+	#[test]
+	fn query() {
+		assert_eq!(
+			query_params("a=1+2&b=%3D%26&&c"),
+			owned(&["a=1 2", "b==&", "c"])
+		);
+		assert_eq!(query_params("a%3Db=c"), owned(&["a=b=c"]));
+		assert_eq!(query_params("=&%20="), owned(&["=", " ="]));
+		assert_eq!(query_params("a=%00"), None);
+		assert_eq!(query_params(""), owned(&[]));
+	}
+
+	#[test]
+	fn encode_round_trip() {
+		let segment = "a b/é%.~";
+		assert_eq!(percent_encode(segment), "a%20b%2F%C3%A9%25.~");
+		assert_eq!(percent_decode(&percent_encode(segment), false).as_deref(), Some(segment));
+	}
+}
@@ -0,0 +1,386 @@
+//! FastCGI, for handing requests to apps that are already running, like
+//! PHP-FPM, instead of starting a process for every request.
+//!
+//! A `.fastcgi` file names the socket of the app, either a unix socket or a
+//! `host:port`:
+//!
+//! ```text
+//! unix:/run/php/php-fpm.sock
+//! ```
+//!
+//! The directory it's in and everything under it is then served by the app,
+//! as a responder.  It gets the same meta-variables as a CGI script (see
+//! `cgi`), with the rest of the path after the directory as `PATH_INFO` and
+//! the file it leads to as `SCRIPT_FILENAME`, and answers the same way.  The
+//! response is streamed as it comes, like it is for CGI.
+
+use std::{io, path::PathBuf};
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::{TcpStream, UnixStream},
+	sync::mpsc,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{
+	cgi::{self, HeaderBlock},
+	log,
+	rewrite,
+	serve::ResponseBody,
+};
+
+/// name of the file naming a FastCGI app
+pub const FASTCGI_FILE: &str = ".fastcgi";
+
+const VERSION: u8 = 1;
+// record types
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+// every connection has only the one request
+const REQUEST_ID: u16 = 1;
+// the most a record can hold
+const MAX_CONTENT: usize = u16::MAX as usize;
+
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+/// The address in a `.fastcgi` file.
+pub fn parse_address(content: &str) -> Option<&str> {
+	rewrite::first_line(content)
+}
+
+async fn connect(address: &str) -> io::Result<Box<dyn Socket>> {
+	match address.strip_prefix("unix:") {
+		Some(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+		None => Ok(Box::new(TcpStream::connect(address).await?)),
+	}
+}
+
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+	let mut record = Vec::with_capacity(8 + content.len());
+	record.extend([VERSION, kind]);
+	record.extend(REQUEST_ID.to_be_bytes());
+	record.extend((content.len() as u16).to_be_bytes());
+	// no padding, and a reserved byte
+	record.extend([0, 0]);
+	record.extend(content);
+	record
+}
+
+// lengths under 128 fit in a byte, longer ones take four with the top bit set
+fn encode_length(len: usize, out: &mut Vec<u8>) {
+	if len < 0x80 {
+		out.push(len as u8);
+	} else {
+		out.extend((len as u32 | 0x8000_0000).to_be_bytes());
+	}
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+	let mut out = Vec::new();
+	for (name, value) in params {
+		encode_length(name.len(), &mut out);
+		encode_length(value.len(), &mut out);
+		out.extend(name.as_bytes());
+		out.extend(value.as_bytes());
+	}
+	out
+}
+
+async fn send(
+	writer: &mut (impl AsyncWrite + Unpin),
+	params: &[(String, String)],
+	mut body: ResponseBody,
+) -> io::Result<()> {
+	let mut begin = RESPONDER.to_be_bytes().to_vec();
+	// no flags, so the app closes the connection when it's done
+	begin.extend([0; 6]);
+	writer.write_all(&record(BEGIN_REQUEST, &begin)).await?;
+	for chunk in encode_params(params).chunks(MAX_CONTENT) {
+		writer.write_all(&record(PARAMS, chunk)).await?;
+	}
+	writer.write_all(&record(PARAMS, &[])).await?;
+	while let Some(frame) = body.frame().await {
+		let Ok(data) = frame?.into_data() else {
+			continue;
+		};
+		for chunk in data.chunks(MAX_CONTENT) {
+			writer.write_all(&record(STDIN, chunk)).await?;
+		}
+	}
+	writer.write_all(&record(STDIN, &[])).await?;
+	writer.flush().await
+}
+
+// passes stdout on until the request ends, and stderr to `stderr` a line
+// at a time
+async fn receive(
+	reader: &mut (impl AsyncRead + Unpin),
+	stdout: &mpsc::Sender<io::Result<Bytes>>,
+	stderr: &mut impl FnMut(&str),
+) -> io::Result<()> {
+	loop {
+		let mut header = [0; 8];
+		reader.read_exact(&mut header).await?;
+		let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+		let mut content = vec![0; len + header[6] as usize];
+		reader.read_exact(&mut content).await?;
+		content.truncate(len);
+		// empty stream records only mark the end of the stream
+		if u16::from_be_bytes([header[2], header[3]]) != REQUEST_ID || content.is_empty() {
+			continue;
+		}
+		match header[1] {
+			// stops when nobody is reading anymore
+			STDOUT => stdout
+				.send(Ok(content.into()))
+				.await
+				.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?,
+			STDERR => {
+				String::from_utf8_lossy(&content).lines().for_each(&mut *stderr);
+			}
+			END_REQUEST if content.len() >= 5 => {
+				let app_status = u32::from_be_bytes([content[0], content[1], content[2], content[3]]);
+				return match (content[4], app_status) {
+					(0, 0) => Ok(()),
+					(0, status) => Err(io::Error::other(format!("app exited with status {}", status))),
+					(protocol, _) => Err(io::Error::other(format!("request rejected with protocol status {}", protocol))),
+				};
+			}
+			_ => {}
+		}
+	}
+}
+
+/// Sends a request to the app at `address`, and reads the header block of its
+/// response.  The body is sent while the response comes back, so an app that
+/// answers early can't get stuck.
+pub async fn request(
+	address: &str,
+	params: Vec<(String, String)>,
+	body: ResponseBody,
+	origin: PathBuf,
+) -> Result<(HeaderBlock, ResponseBody), String> {
+	let socket = connect(address)
+		.await
+		.map_err(|e| format!("could not connect to {}: {}", address, e))?;
+	let (mut reader, mut writer) = tokio::io::split(socket);
+	let sending = origin.clone();
+	tokio::spawn(async move {
+		if let Err(e) = send(&mut writer, &params, body).await {
+			log!(error "FASTCGI"; "Could not send request for {}: {}", sending.display(), e);
+		}
+	});
+	let (tx, rx) = mpsc::channel(16);
+	tokio::spawn(async move {
+		let mut stderr = |line: &str| log!(error "FASTCGI"; "{}: {}", origin.display(), line);
+		if let Err(e) = receive(&mut reader, &tx, &mut stderr).await {
+			let _ = tx.send(Err(e)).await;
+		}
+	});
+	let mut stdout = StreamReader::new(Box::pin(stream::unfold(rx, |mut rx| async move {
+		rx.recv().await.map(|item| (item, rx))
+	})));
+	let mut buffered = Vec::new();
+	let block = cgi::read_headers(&mut stdout, &mut buffered).await?;
+	let frames = stream::iter([Ok(Bytes::from(buffered))])
+		.chain(ReaderStream::new(stdout))
+		.map_ok(Frame::data);
+	Ok((block, StreamBody::new(frames).boxed_unsync()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tokio::{net::UnixListener, sync::oneshot};
+
+	use super::*;
+
+	fn end_request(app_status: u32, protocol: u8) -> Vec<u8> {
+		let mut content = app_status.to_be_bytes().to_vec();
+		content.extend([protocol, 0, 0, 0]);
+		record(END_REQUEST, &content)
+	}
+
+	// the type and content of the next record
+	async fn read_record(reader: &mut (impl AsyncRead + Unpin)) -> (u8, Vec<u8>) {
+		let mut header = [0; 8];
+		reader.read_exact(&mut header).await.unwrap();
+		assert_eq!(header[0], VERSION);
+		assert_eq!(u16::from_be_bytes([header[2], header[3]]), REQUEST_ID);
+		let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+		let mut content = vec![0; len + header[6] as usize];
+		reader.read_exact(&mut content).await.unwrap();
+		content.truncate(len);
+		(header[1], content)
+	}
+
+	fn decode_length(data: &mut &[u8]) -> usize {
+		if data[0] < 0x80 {
+			let len = data[0] as usize;
+			*data = &data[1..];
+			len
+		} else {
+			let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) & 0x7fff_ffff;
+			*data = &data[4..];
+			len as usize
+		}
+	}
+
+	fn decode_params(mut data: &[u8]) -> Vec<(String, String)> {
+		let mut params = Vec::new();
+		while !data.is_empty() {
+			let name_len = decode_length(&mut data);
+			let value_len = decode_length(&mut data);
+			let name = String::from_utf8(data[..name_len].to_vec()).unwrap();
+			let value = String::from_utf8(data[name_len..name_len + value_len].to_vec()).unwrap();
+			data = &data[name_len + value_len..];
+			params.push((name, value));
+		}
+		params
+	}
+
+	#[test]
+	fn long_params() {
+		let long = "x".repeat(200);
+		let params = vec![("A".to_string(), String::new()), (long.clone(), long)];
+		let encoded = encode_params(&params);
+		assert_eq!(&encoded[..3], [1, 0, b'A']);
+		assert_eq!(&encoded[3..11], [0x80, 0, 0, 200, 0x80, 0, 0, 200]);
+		assert_eq!(decode_params(&encoded), params);
+	}
+
+	#[tokio::test]
+	async fn receive_records() {
+		let mut input = record(STDOUT, b"Status: 200\r\n\r\nhel");
+		// padded, and a request that isn't ours
+		input.extend([VERSION, STDOUT, 0, 1, 0, 2, 3, 0, b'l', b'o', 0, 0, 0]);
+		input.extend([VERSION, STDOUT, 0, 2, 0, 5, 0, 0]);
+		input.extend(b"other");
+		input.extend(record(STDERR, b"first line\nsecond line\n"));
+		input.extend(record(STDOUT, &[]));
+		input.extend(end_request(3, 0));
+		input.extend(record(STDOUT, b"after the end"));
+
+		let (tx, mut rx) = mpsc::channel(16);
+		let mut lines = Vec::new();
+		let result = receive(&mut input.as_slice(), &tx, &mut |l: &str| lines.push(l.to_string())).await;
+		assert_eq!(result.unwrap_err().to_string(), "app exited with status 3");
+		assert_eq!(lines, ["first line", "second line"]);
+		drop(tx);
+		let mut stdout = Vec::new();
+		while let Some(chunk) = rx.recv().await {
+			stdout.extend(chunk.unwrap());
+		}
+		assert_eq!(stdout, b"Status: 200\r\n\r\nhello");
+	}
+
+	#[tokio::test]
+	async fn receive_rejected() {
+		let input = end_request(0, 3);
+		let (tx, _rx) = mpsc::channel(16);
+		let result = receive(&mut input.as_slice(), &tx, &mut |_: &str| {}).await;
+		assert_eq!(result.unwrap_err().to_string(), "request rejected with protocol status 3");
+	}
+
+	// answers one request, echoing its stdin, once it's been told the
+	// first chunk arrived
+	async fn responder(listener: UnixListener, seen: oneshot::Sender<()>, app_status: u32) -> Vec<(String, String)> {
+		let (mut socket, _) = listener.accept().await.unwrap();
+		let (kind, begin) = read_record(&mut socket).await;
+		assert_eq!(kind, BEGIN_REQUEST);
+		assert_eq!(begin, [0, 1, 0, 0, 0, 0, 0, 0]);
+		let mut params = Vec::new();
+		loop {
+			let (kind, content) = read_record(&mut socket).await;
+			assert_eq!(kind, PARAMS);
+			if content.is_empty() {
+				break;
+			}
+			params.extend(content);
+		}
+		// the headers go out before the body is all in
+		socket
+			.write_all(&record(STDOUT, b"Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n"))
+			.await
+			.unwrap();
+		let mut seen = Some(seen);
+		let mut stdin = Vec::new();
+		loop {
+			let (kind, content) = read_record(&mut socket).await;
+			assert_eq!(kind, STDIN);
+			if content.is_empty() {
+				break;
+			}
+			stdin.extend(content);
+			if let Some(seen) = seen.take() {
+				seen.send(()).unwrap();
+			}
+		}
+		socket.write_all(&record(STDERR, b"done reading\n")).await.unwrap();
+		socket.write_all(&record(STDOUT, &stdin)).await.unwrap();
+		socket.write_all(&record(STDOUT, &[])).await.unwrap();
+		socket.write_all(&end_request(app_status, 0)).await.unwrap();
+		decode_params(&params)
+	}
+
+	async fn exchange(app_status: u32) -> (HeaderBlock, Result<Bytes, io::Error>, Vec<(String, String)>) {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("app.sock");
+		let listener = UnixListener::bind(&path).unwrap();
+		let (seen_tx, seen_rx) = oneshot::channel();
+		let app = tokio::spawn(responder(listener, seen_tx, app_status));
+
+		let (chunks, rx) = mpsc::channel::<io::Result<Frame<Bytes>>>(1);
+		let body = StreamBody::new(stream::unfold(rx, |mut rx| async move {
+			rx.recv().await.map(|item| (item, rx))
+		}))
+			.boxed_unsync();
+		let params = vec![
+			("SCRIPT_FILENAME".to_string(), "/srv/index.php".to_string()),
+			("QUERY_STRING".to_string(), "a=1".to_string()),
+		];
+		let address = format!("unix:{}", path.display());
+		let (block, body) = request(&address, params, body, PathBuf::from("/srv")).await.unwrap();
+
+		chunks.send(Ok(Frame::data(Bytes::from("first ")))).await.unwrap();
+		// the rest only once the app has the start, so it has to be streamed
+		tokio::time::timeout(Duration::from_secs(5), seen_rx)
+			.await
+			.expect("stdin was not streamed")
+			.unwrap();
+		chunks.send(Ok(Frame::data(Bytes::from("second")))).await.unwrap();
+		drop(chunks);
+		let body = body.collect().await.map(|b| b.to_bytes());
+		(block, body, app.await.unwrap())
+	}
+
+	#[tokio::test]
+	async fn responder_exchange() {
+		let ((headers, status), body, params) = exchange(0).await;
+		assert_eq!(status, Some(201));
+		assert_eq!(headers, [("Content-Type".to_string(), "text/plain".to_string())]);
+		assert_eq!(body.unwrap(), "first second");
+		assert_eq!(params, [
+			("SCRIPT_FILENAME".to_string(), "/srv/index.php".to_string()),
+			("QUERY_STRING".to_string(), "a=1".to_string()),
+		]);
+	}
+
+	#[tokio::test]
+	async fn responder_failure() {
+		let ((_, status), body, _) = exchange(2).await;
+		assert_eq!(status, Some(201));
+		assert_eq!(body.unwrap_err().to_string(), "app exited with status 2");
+	}
+}
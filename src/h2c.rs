@@ -0,0 +1,357 @@
+//! Upgrade-based h2c (RFC 7540, section 3.2).
+//!
+//! hyper-util's auto builder already notices prior-knowledge h2c by looking
+//! for the connection preface, but hyper has no way to adopt the HTTP/1.1
+//! request that asked for the upgrade as stream 1 of the new connection.  So,
+//! after answering it with `101 Switching Protocols`, the request is encoded
+//! as an HTTP/2 HEADERS frame and spliced into the byte stream right after the
+//! client's preface and first SETTINGS frame.  The HTTP/2 server then sees it
+//! like any other request, and answers it on stream 1 as the client expects.
+//!
+//! The settings in the request's `HTTP2-Settings` header are the client's
+//! initial ones, which the 101 acknowledges.  They're put in front of those
+//! of the client's first SETTINGS frame, which can override them, so the
+//! server applies them without an acknowledgement of their own.  A request
+//! whose settings don't decode, or aren't valid, is answered with a 400.
+
+use std::{
+	io,
+	pin::Pin,
+	task::{ready, Context, Poll},
+};
+
+use hyper::{
+	body::{Body, Incoming},
+	header::{HeaderValue, CONNECTION, HOST, UPGRADE},
+	Request, Version,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+// default SETTINGS_MAX_FRAME_SIZE, the client can't have lowered it yet.
+const MAX_FRAME_LEN: usize = 16384;
+const SETTINGS: u8 = 0x4;
+const ACK: u8 = 0x1;
+// identifier and value
+const SETTING_LEN: usize = 6;
+
+// headers that are specific to the HTTP/1.1 connection, and are not allowed
+// to be carried over into HTTP/2.
+const CONNECTION_HEADERS: &[&str] = &[
+	"connection", "upgrade", "http2-settings", "host", "keep-alive",
+	"proxy-connection", "transfer-encoding", "te",
+];
+
+fn has_token(value: Option<&HeaderValue>, token: &str) -> bool {
+	value
+		.and_then(|v| v.to_str().ok())
+		.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+// base64url without padding, which is what `HTTP2-Settings` is in
+fn decode_base64url(encoded: &[u8]) -> Option<Vec<u8>> {
+	let encoded = encoded.strip_suffix(b"==").or(encoded.strip_suffix(b"=")).unwrap_or(encoded);
+	let sextet = |c: u8| match c {
+		b'A'..=b'Z' => Some(c - b'A'),
+		b'a'..=b'z' => Some(c - b'a' + 26),
+		b'0'..=b'9' => Some(c - b'0' + 52),
+		b'-' => Some(62),
+		b'_' => Some(63),
+		_ => None,
+	};
+	// a single character left over can't make a byte
+	if encoded.len() % 4 == 1 {
+		return None;
+	}
+	let mut out = Vec::with_capacity(encoded.len() * 3 / 4);
+	for chunk in encoded.chunks(4) {
+		let mut bits = 0u32;
+		for c in chunk {
+			bits = bits << 6 | sextet(*c)? as u32;
+		}
+		bits <<= 6 * (4 - chunk.len()) as u32;
+		out.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+	}
+	Some(out)
+}
+
+/// The settings of an `HTTP2-Settings` header, as the payload of a SETTINGS
+/// frame.  Only the ones this server knows are kept, the last of each, so
+/// they're sure to fit in front of the client's own.
+fn parse_settings(value: &[u8]) -> Result<Vec<u8>, String> {
+	let payload = decode_base64url(value).ok_or("HTTP2-Settings is not base64url")?;
+	if payload.len() % SETTING_LEN != 0 {
+		return Err("HTTP2-Settings is not a list of settings".to_string());
+	}
+	let mut settings: Vec<(u16, u32)> = Vec::new();
+	for setting in payload.chunks(SETTING_LEN) {
+		let id = u16::from_be_bytes([setting[0], setting[1]]);
+		let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+		// see RFC 9113, section 6.5.2
+		let valid = match id {
+			0x1 | 0x3 | 0x6 => true,
+			0x2 => value <= 1,
+			0x4 => value <= 0x7fff_ffff,
+			0x5 => (16384..=0xff_ffff).contains(&value),
+			_ => continue,
+		};
+		if !valid {
+			return Err(format!("HTTP2-Settings has {} for setting {:#x}", value, id));
+		}
+		settings.retain(|(i, _)| *i != id);
+		settings.push((id, value));
+	}
+	Ok(settings
+		.into_iter()
+		.flat_map(|(id, value)| [&id.to_be_bytes()[..], &value.to_be_bytes()[..]].concat())
+		.collect())
+}
+
+/// What's needed to carry on a request over HTTP/2 after upgrading to it.
+pub struct Upgrade {
+	// the HEADERS frame that replays it as stream 1
+	frame: Vec<u8>,
+	// what `HTTP2-Settings` set, as a SETTINGS payload
+	settings: Vec<u8>,
+}
+
+/// If the request asks to be upgraded to h2c, and it can be, returns what's
+/// needed to replay it as stream 1 after the upgrade.  Fails when the
+/// settings it came with aren't valid.
+///
+/// Requests with a body are not upgraded: the body would have to be read in
+/// full before switching protocols, so they are just answered over HTTP/1.1,
+/// which the RFC allows.
+pub fn upgrade(req: &Request<Incoming>) -> Result<Option<Upgrade>, String> {
+	if req.version() != Version::HTTP_11
+		|| !has_token(req.headers().get(UPGRADE), "h2c")
+		|| !has_token(req.headers().get(CONNECTION), "http2-settings")
+	{
+		return Ok(None);
+	}
+	let mut values = req.headers().get_all("http2-settings").iter();
+	let settings = match (values.next(), values.next()) {
+		(Some(value), None) => parse_settings(value.as_bytes())?,
+		(None, _) => return Ok(None),
+		(Some(_), Some(_)) => return Err("more than one HTTP2-Settings".to_string()),
+	};
+	if !req.body().is_end_stream() {
+		return Ok(None);
+	}
+	let authority = req
+		.headers()
+		.get(HOST)
+		.map(HeaderValue::as_bytes)
+		.or(req.uri().authority().map(|a| a.as_str().as_bytes()))
+		.unwrap_or_default();
+	let path = req
+		.uri()
+		.path_and_query()
+		.map(|p| p.as_str())
+		.unwrap_or("/");
+
+	let mut block = Vec::new();
+	encode_header(&mut block, b":method", req.method().as_str().as_bytes());
+	encode_header(&mut block, b":scheme", b"http");
+	encode_header(&mut block, b":authority", authority);
+	encode_header(&mut block, b":path", path.as_bytes());
+	for (name, value) in req.headers() {
+		if CONNECTION_HEADERS.contains(&name.as_str()) {
+			continue;
+		}
+		encode_header(&mut block, name.as_str().as_bytes(), value.as_bytes());
+	}
+	// no CONTINUATION frames, just stay on HTTP/1.1 for huge requests
+	if block.len() > MAX_FRAME_LEN {
+		return Ok(None);
+	}
+
+	let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + block.len());
+	frame.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+	// type HEADERS, flags END_STREAM | END_HEADERS, stream 1
+	frame.extend_from_slice(&[0x1, 0x1 | 0x4]);
+	frame.extend_from_slice(&1u32.to_be_bytes());
+	frame.extend(block);
+	Ok(Some(Upgrade { frame, settings }))
+}
+
+// HPACK literal header field without indexing, with a new name and no
+// huffman coding.  Simple, and every decoder has to accept it.
+fn encode_header(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+	out.push(0);
+	encode_string(out, name);
+	encode_string(out, value);
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &[u8]) {
+	// 7 bit prefixed integer, see RFC 7541 section 5.1
+	const PREFIX_MAX: usize = 127;
+	let mut len = s.len();
+	if len < PREFIX_MAX {
+		out.push(len as u8);
+	} else {
+		out.push(PREFIX_MAX as u8);
+		len -= PREFIX_MAX;
+		while len >= 128 {
+			out.push((len % 128 + 128) as u8);
+			len /= 128;
+		}
+		out.push(len as u8);
+	}
+	out.extend_from_slice(s);
+}
+
+/// Wraps the upgraded connection, and injects a HEADERS frame once the
+/// client's connection preface has gone by, with the settings from the
+/// upgrade added to its first SETTINGS frame.
+pub struct Spliced<T> {
+	inner: T,
+	frame: Option<Vec<u8>>,
+	settings: Vec<u8>,
+	head: Vec<u8>,
+	out: Vec<u8>,
+	out_pos: usize,
+}
+
+impl<T> Spliced<T> {
+	pub fn new(inner: T, upgrade: Upgrade) -> Self {
+		Spliced {
+			inner,
+			frame: Some(upgrade.frame),
+			settings: upgrade.settings,
+			head: Vec::new(),
+			out: Vec::new(),
+			out_pos: 0,
+		}
+	}
+
+	/// Where the client's first SETTINGS frame ends, if it's all been read.
+	fn preface_end(&self) -> Option<usize> {
+		let header = PREFACE.len() + FRAME_HEADER_LEN;
+		if self.head.len() < header {
+			return None;
+		}
+		let len = u32::from_be_bytes([0, self.head[24], self.head[25], self.head[26]]) as usize;
+		Some(header + len).filter(|end| *end <= self.head.len())
+	}
+
+	/// The preface, with the upgrade's settings before the client's own.
+	/// Anything but a SETTINGS frame is left for the HTTP/2 server to refuse.
+	fn preface(&self, end: usize) -> Vec<u8> {
+		let (preface, frame) = self.head[..end].split_at(PREFACE.len());
+		let (header, payload) = frame.split_at(FRAME_HEADER_LEN);
+		let len = self.settings.len() + payload.len();
+		if header[3] != SETTINGS || header[4] & ACK != 0 || len > MAX_FRAME_LEN {
+			return self.head[..end].to_vec();
+		}
+		let mut out = Vec::with_capacity(PREFACE.len() + FRAME_HEADER_LEN + len);
+		out.extend_from_slice(preface);
+		out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+		out.extend_from_slice(&header[3..]);
+		out.extend_from_slice(&self.settings);
+		out.extend_from_slice(payload);
+		out
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Spliced<T> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		loop {
+			if this.out_pos < this.out.len() {
+				let n = buf.remaining().min(this.out.len() - this.out_pos);
+				buf.put_slice(&this.out[this.out_pos..this.out_pos + n]);
+				this.out_pos += n;
+				return Poll::Ready(Ok(()));
+			}
+			let Some(frame) = &this.frame else {
+				return Pin::new(&mut this.inner).poll_read(cx, buf);
+			};
+			// not a valid preface, let the HTTP/2 server deal with it
+			let valid = this.head.len() < PREFACE.len() && PREFACE.starts_with(&this.head)
+				|| this.head.starts_with(PREFACE);
+			if !valid {
+				this.frame = None;
+				this.out = std::mem::take(&mut this.head);
+				continue;
+			}
+			if let Some(end) = this.preface_end() {
+				let mut out = this.preface(end);
+				out.extend_from_slice(frame);
+				out.extend_from_slice(&this.head[end..]);
+				this.out = out;
+				this.out_pos = 0;
+				this.frame = None;
+				this.head = Vec::new();
+				continue;
+			}
+			let mut tmp = [0u8; 4096];
+			let mut read = ReadBuf::new(&mut tmp);
+			ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+			if read.filled().is_empty() {
+				// closed before finishing the preface
+				this.frame = None;
+				this.out = std::mem::take(&mut this.head);
+				this.out_pos = 0;
+				continue;
+			}
+			this.head.extend_from_slice(read.filled());
+		}
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Spliced<T> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn base64url() {
+		assert_eq!(decode_base64url(b"").unwrap(), b"");
+		assert_eq!(decode_base64url(b"AAMAAABk").unwrap(), [0, 3, 0, 0, 0, 100]);
+		assert_eq!(decode_base64url(b"_-8").unwrap(), [0xff, 0xef]);
+		// padding is tolerated, though it shouldn't be there
+		assert_eq!(decode_base64url(b"_-8=").unwrap(), [0xff, 0xef]);
+		assert!(decode_base64url(b"AAMA+/8").is_none());
+		assert!(decode_base64url(b"AAMAA").is_none());
+		assert!(decode_base64url(b"AA MA").is_none());
+	}
+
+	#[test]
+	fn settings() {
+		// max concurrent streams 100, then initial window size 2^30, twice
+		let parsed = parse_settings(b"AAMAAABkAARAAAAAAAQAAAAB").unwrap();
+		assert_eq!(parsed, [0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 0, 1]);
+		assert_eq!(parse_settings(b"").unwrap(), b"");
+		// unknown settings are ignored
+		assert_eq!(parse_settings(b"AP8AAAAB").unwrap(), b"");
+		assert!(parse_settings(b"AAMAAAB").is_err());
+		assert!(parse_settings(b"AAMAAABkAA").is_err());
+		// push can only be on or off, and frames can't be smaller than 16384
+		assert!(parse_settings(b"AAIAAAAC").is_err());
+		assert!(parse_settings(b"AAUAAAAB").is_err());
+		assert!(parse_settings(b"AAT_____").is_err());
+	}
+}
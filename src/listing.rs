@@ -0,0 +1,69 @@
+//! Directory listings, for directories without a handler when their
+//! `.config` has `listing = true`.  Hidden entries and regex directories are
+//! left out, the same as they can't be requested directly.
+
+use std::{fmt::Write, io, path::Path};
+
+use tokio::fs;
+
+use crate::decode;
+
+fn escape_html(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// An HTML page linking to everything visible in `dir`, which was requested
+/// as `uri_path`.
+pub async fn render(dir: &Path, uri_path: &str) -> io::Result<String> {
+	let mut entries = Vec::new();
+	let mut read = fs::read_dir(dir).await?;
+	while let Some(entry) = read.next_entry().await? {
+		let Some(name) = entry.file_name().to_str().map(String::from) else {
+			continue;
+		};
+		if name.starts_with(".") || name.starts_with("&") {
+			continue;
+		}
+		// follows symlinks, so linked directories are listed as directories
+		let is_dir = fs::metadata(entry.path()).await.is_ok_and(|m| m.is_dir());
+		entries.push((name, is_dir));
+	}
+	// directories first, then by name
+	entries.sort_by(|(a, a_dir), (b, b_dir)| b_dir.cmp(a_dir).then_with(|| a.cmp(b)));
+
+	let base = escape_html(uri_path.trim_end_matches("/"));
+	let title = format!("Index of {}/", base);
+	let mut page = format!(
+		"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n<ul>\n",
+		title
+	);
+	if !base.is_empty() {
+		let parent = base.rsplit_once("/").map_or("", |(parent, _)| parent);
+		let _ = writeln!(page, "<li><a href=\"{}/\">../</a></li>", parent);
+	}
+	for (name, is_dir) in entries {
+		let slash = if is_dir { "/" } else { "" };
+		let _ = writeln!(
+			page,
+			"<li><a href=\"{}/{}{}\">{}{}</a></li>",
+			base,
+			decode::percent_encode(&name),
+			slash,
+			escape_html(&name),
+			slash
+		);
+	}
+	page.push_str("</ul>\n</body>\n</html>\n");
+	Ok(page)
+}
@@ -1,22 +1,46 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, io};
 use std::path::PathBuf;
 
+use http_body_util::{BodyExt, Empty};
+use hyper::{body::Incoming, header, Request, Response, StatusCode};
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::crypto::CryptoProvider;
+use rustls::sign::CertifiedKey;
 use rustls::ServerConfig;
 use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
 
-use hyper::server::conn::http1;
+use hyper::server::conn::{http1, http2};
 
 use std::env;
 
+mod cgi;
+mod compress;
+mod conditional;
+mod decode;
+mod fastcgi;
+mod h2c;
+mod listing;
+mod mime;
+mod proxy;
+mod range;
+mod rewrite;
+mod routes;
 mod serve;
+mod settings;
+mod vhost;
+mod workers;
 
-use serve::{serve, EXIT_CODES};
+use cgi::Peer;
+use serve::{serve, Config, ResponseBody, EXIT_CODES};
+use vhost::{Hosts, SniResolver};
 
 use clap::Parser;
 #[derive(Parser, Debug)]
@@ -40,7 +64,106 @@ struct Args {
 
 	/// Path to the certificate file.
 	#[arg(short, long)]
-	private_key: Option<String>
+	private_key: Option<String>,
+
+	/// Pipe request bodies straight into the first executable as they
+	/// arrive, instead of buffering them to a tempfile first.
+	#[arg(long)]
+	stream_request_body: bool,
+
+	/// Only use extensions and `.mime` files to decide the type of static
+	/// files, never their contents.
+	#[arg(long)]
+	no_mime_sniffing: bool,
+
+	/// Hash the contents of static files for strong ETags, instead of using
+	/// weak ones made from their size and modification time.
+	#[arg(long)]
+	strong_etags: bool,
+
+	/// Never compress responses.
+	#[arg(long)]
+	no_compression: bool,
+
+	/// Smallest body, in bytes, that is worth compressing.
+	#[arg(long, default_value_t = 1024)]
+	compress_min_size: u64,
+
+	/// Comma separated `Content-Type`s to compress.  `type/*` covers every
+	/// subtype.
+	#[arg(long, value_delimiter = ',', default_values = compress::DEFAULT_TYPES)]
+	compress_types: Vec<String>,
+
+	/// Serve a host from its own folder, as `<name>=<folder>`.  Can be given
+	/// more than once.
+	#[arg(long = "host", value_name = "NAME=FOLDER", value_parser = vhost::parse_mapping)]
+	hosts: Vec<(String, String)>,
+
+	/// Serve hosts that don't have a folder of their own from the folder
+	/// named after them in the base folder.
+	#[arg(long)]
+	vhost_dirs: bool,
+
+	/// Host to serve requests for unknown hosts as.
+	#[arg(long, value_name = "NAME")]
+	default_host: Option<String>,
+
+	/// Certificate for a host, picked by SNI, as `<name>=<cert>,<key>`.  Can
+	/// be given more than once.
+	#[arg(long = "host-cert", value_name = "NAME=CERT,KEY", value_parser = vhost::parse_mapping)]
+	host_certs: Vec<(String, String)>,
+
+	/// Seconds a handler can run for before it's killed and answered with a
+	/// 504, unless its `.config` says otherwise.  No limit by default.
+	#[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+	timeout: Option<Duration>,
+
+	#[command(flatten)]
+	http2: Http2Options,
+}
+
+fn parse_timeout(arg: &str) -> Result<Duration, String> {
+	arg.parse::<f64>()
+		.ok()
+		.and_then(|t| Duration::try_from_secs_f64(t).ok())
+		.ok_or(format!("{} is not a number of seconds", arg))
+}
+
+/// Settings for connections that are served over HTTP/2.
+#[derive(clap::Args, Debug, Clone, Copy)]
+struct Http2Options {
+	/// Maximum number of concurrent streams per HTTP/2 connection.
+	#[arg(long, default_value_t = 200)]
+	h2_max_concurrent_streams: u32,
+
+	/// Initial HTTP/2 stream-level flow control window, in bytes.
+	#[arg(long)]
+	h2_stream_window: Option<u32>,
+
+	/// Initial HTTP/2 connection-level flow control window, in bytes.
+	#[arg(long)]
+	h2_connection_window: Option<u32>,
+}
+
+impl Http2Options {
+	fn builder(&self) -> http2::Builder<TokioExecutor> {
+		let mut builder = http2::Builder::new(TokioExecutor::new());
+		builder
+			.max_concurrent_streams(self.h2_max_concurrent_streams)
+			.initial_stream_window_size(self.h2_stream_window)
+			.initial_connection_window_size(self.h2_connection_window);
+		builder
+	}
+
+	/// Builder that detects HTTP/1 or prior-knowledge HTTP/2 per connection.
+	fn auto_builder(&self) -> auto::Builder<TokioExecutor> {
+		let mut builder = auto::Builder::new(TokioExecutor::new());
+		builder.http2()
+			.max_concurrent_streams(self.h2_max_concurrent_streams)
+			.initial_stream_window_size(self.h2_stream_window)
+			.initial_connection_window_size(self.h2_connection_window);
+		builder
+	}
 }
 
 #[tokio::main]
@@ -67,6 +190,25 @@ async fn main() {
 		println!("Base directory is not a directory!");
 		return
 	}
+	let mut roots = HashMap::new();
+	for (host, root) in &args.hosts {
+		match PathBuf::from(root).canonicalize() {
+			Ok(root) if root.is_dir() => {
+				roots.insert(host.clone(), root);
+			}
+			_ => {
+				println!("Folder for host {} is not a directory!", host);
+				return
+			}
+		}
+	}
+
+	// dropping the watchers would stop the route cache from being updated
+	let mut watchers = Vec::new();
+	for root in [&basedir].into_iter().chain(roots.values()) {
+		watchers.extend(routes::watch(root));
+		routes::check(root);
+	}
 
 	// note: this operation is unsafe iff there are other threads running.
 	// This is before any other threads start up, so according to docs should
@@ -77,10 +219,21 @@ async fn main() {
 		}
 	}
 	
+	let config = Arc::new(Config {
+		stream_request_body: args.stream_request_body,
+		sniff_mime: !args.no_mime_sniffing,
+		strong_etags: args.strong_etags,
+		compress: !args.no_compression,
+		compress_min_size: args.compress_min_size,
+		compress_types: args.compress_types.clone(),
+		hosts: Hosts::new(roots, args.vhost_dirs, args.default_host.clone()),
+		timeout: args.timeout,
+	});
+
 	if let Err(e) = if args.use_http {
-		http_server(listener, basedir).await
+		http_server(listener, basedir, config, args.http2).await
 	} else {
-		https_server(listener, basedir, args).await
+		https_server(listener, basedir, config, args).await
 	} {
 		println!("{}", e);
 	};
@@ -90,7 +243,7 @@ fn error(err: String) -> io::Error {
 	std::io::Error::other(err)
 }
 
-async fn https_server(listener: TcpListener, basedir: PathBuf, args: Args) -> Result<
+async fn https_server(listener: TcpListener, basedir: PathBuf, config: Arc<Config>, args: Args) -> Result<
 		(),
 		Box<dyn std::error::Error + Send + Sync>
 	> {
@@ -101,25 +254,38 @@ async fn https_server(listener: TcpListener, basedir: PathBuf, args: Args) -> Re
 	let certfile = args.certificate.ok_or(error(
 		"HTTPS requires a certificate file to be given!".into()
 	))?;
-	let certs = load_certs(certfile.as_str())?;
 	// Load private key.
 	let keyfile = args.private_key.ok_or(error(
 		"HTTPS requires a certificate file to be given!".into()
 	))?;
-	let key = load_private_key(keyfile.as_str())?;
+	let default = load_certified_key(&certfile, &keyfile)?;
+	// Certificates for virtual hosts, picked by SNI.
+	let mut host_certs = HashMap::new();
+	for (host, files) in &args.host_certs {
+		let (certfile, keyfile) = files.split_once(",").ok_or(error(format!(
+			"Certificate for {} should be given as <cert>,<key>", host
+		)))?;
+		host_certs.insert(host.clone(), load_certified_key(certfile, keyfile)?);
+	}
 
 	// Build TLS configuration.
 	let mut server_config = ServerConfig::builder()
 		.with_no_client_auth()
-		.with_single_cert(certs, key)
-		.map_err(|e| error(e.to_string()))?;
+		.with_cert_resolver(Arc::new(SniResolver::new(host_certs, default)));
 	server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
 	let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+	let http2 = args.http2;
 
 	loop {
 		let basedir = basedir.clone();
+		let config = config.clone();
 		let (tcp_stream, addr) = listener.accept().await?;
 		log!(info "INFO"; "connection with {} accepted.", addr);
+		let peer = Peer {
+			remote: addr,
+			local: tcp_stream.local_addr()?,
+			secure: true,
+		};
 		let tls_acceptor = tls_acceptor.clone();
 		tokio::spawn(async move {
 			let tls_stream = match tls_acceptor.accept(tcp_stream).await {
@@ -129,14 +295,30 @@ async fn https_server(listener: TcpListener, basedir: PathBuf, args: Args) -> Re
 					return;
 				}
 			};
-			if let Err(err) = http1::Builder::new()
-				.serve_connection(
-					TokioIo::new(tls_stream),
-					service_fn(|req|
-						serve(req, basedir.clone())
+			// the client picks the protocol during the handshake, so only hand the
+			// connection to the HTTP/2 server if that is what was negotiated.
+			let is_h2 = tls_stream.get_ref().1.alpn_protocol() == Some(b"h2");
+			let res = if is_h2 {
+				http2.builder()
+					.serve_connection(
+						TokioIo::new(tls_stream),
+						service_fn(|req|
+							serve(req, basedir.clone(), config.clone(), peer)
+						)
+					).await
+			} else {
+				http1::Builder::new()
+					.serve_connection(
+						TokioIo::new(tls_stream),
+						service_fn(|req|
+							serve(req, basedir.clone(), config.clone(), peer)
+						)
 					)
-				).await
-			{
+					// so proxied WebSockets can take over the connection
+					.with_upgrades()
+					.await
+			};
+			if let Err(err) = res {
 				eprintln!("failed to serve connection: {err:#}");
 			};
 		});
@@ -154,6 +336,15 @@ fn load_certs(filename: &str) -> io::Result<Vec<CertificateDer<'static>>> {
 	rustls_pemfile::certs(&mut reader).collect()
 }
 
+// Load a certificate and its private key, checking that they go together.
+fn load_certified_key(certfile: &str, keyfile: &str) -> io::Result<Arc<CertifiedKey>> {
+	let provider = CryptoProvider::get_default()
+		.ok_or(error("No crypto provider installed".into()))?;
+	let key = CertifiedKey::from_der(load_certs(certfile)?, load_private_key(keyfile)?, provider)
+		.map_err(|e| error(format!("{} and {} don't go together: {}", certfile, keyfile, e)))?;
+	Ok(Arc::new(key))
+}
+
 // Load private key from file.
 fn load_private_key(filename: &str) -> io::Result<PrivateKeyDer<'static>> {
 	// Open keyfile.
@@ -165,26 +356,38 @@ fn load_private_key(filename: &str) -> io::Result<PrivateKeyDer<'static>> {
 	rustls_pemfile::private_key(&mut reader).map(|key| key.unwrap())
 }
 
-async fn http_server(listener: TcpListener, basedir: PathBuf) -> Result<
+async fn http_server(
+	listener: TcpListener,
+	basedir: PathBuf,
+	config: Arc<Config>,
+	http2: Http2Options
+) -> Result<
 		(),
 		Box<dyn std::error::Error + Send + Sync>
 		> {
 	loop {
 		let (tcp_stream, addr) = listener.accept().await?;
 		log!(info "INFO"; "connection with {} accepted.", addr);
+		let peer = Peer {
+			remote: addr,
+			local: tcp_stream.local_addr()?,
+			secure: false,
+		};
 		let basedir = basedir.clone();
+		let config = config.clone();
 		// Use an adapter to access something implementing `tokio::io` traits as if they implement
 		// `hyper::rt` IO traits.
 
 		// Spawn a tokio task to serve multiple connections concurrently
 		tokio::task::spawn(async move {
-			// Finally, we bind the incoming connection to our `hello` service
-			if let Err(err) = http1::Builder::new()
+			// Finally, we bind the incoming connection to our `hello` service.
+			// Whether it speaks HTTP/1 or h2c is decided by the first bytes sent.
+			if let Err(err) = http2.auto_builder()
 				// `service_fn` converts our function in a `Service`
-				.serve_connection(
+				.serve_connection_with_upgrades(
 					TokioIo::new(tcp_stream),
 					service_fn(|req| {
-						serve(req, basedir.clone())
+						serve_h2c_upgrade(req, basedir.clone(), config.clone(), http2, peer)
 					})
 				).await
 			{
@@ -193,3 +396,53 @@ async fn http_server(listener: TcpListener, basedir: PathBuf) -> Result<
 		});
 	}
 }
+
+/// Serves a request on the plain listener, switching the connection over to
+/// HTTP/2 first if it asked for an h2c upgrade.
+async fn serve_h2c_upgrade(
+	mut req: Request<Incoming>,
+	basedir: PathBuf,
+	config: Arc<Config>,
+	http2: Http2Options,
+	peer: Peer
+) -> Result<
+		Response<ResponseBody>,
+		http::Error
+	> {
+	let upgrade = match h2c::upgrade(&req) {
+		Ok(Some(upgrade)) => upgrade,
+		Ok(None) => return serve(req, basedir, config, peer).await,
+		Err(e) => {
+			log!(error "ERROR"; "Refused h2c upgrade from {}: {}", peer.remote, e);
+			return Response::builder()
+				.status(StatusCode::BAD_REQUEST)
+				.body(Empty::new().map_err(|e| match e {}).boxed_unsync());
+		}
+	};
+	let on_upgrade = hyper::upgrade::on(&mut req);
+	tokio::spawn(async move {
+		let upgraded = match on_upgrade.await {
+			Ok(upgraded) => upgraded,
+			Err(err) => {
+				eprintln!("failed to upgrade to h2c: {err:#}");
+				return;
+			}
+		};
+		// the upgrade request is replayed as stream 1, and served from there
+		if let Err(err) = http2.builder()
+			.serve_connection(
+				TokioIo::new(h2c::Spliced::new(TokioIo::new(upgraded), upgrade)),
+				service_fn(|req|
+					serve(req, basedir.clone(), config.clone(), peer)
+				)
+			).await
+		{
+			eprintln!("Error serving upgraded connection: {:?}", err);
+		}
+	});
+	Response::builder()
+		.status(StatusCode::SWITCHING_PROTOCOLS)
+		.header(header::CONNECTION, "Upgrade")
+		.header(header::UPGRADE, "h2c")
+		.body(Empty::new().map_err(|e| match e {}).boxed_unsync())
+}
@@ -0,0 +1,238 @@
+//! Figuring out the `Content-Type` of static files, without shelling out.
+//!
+//! In order, the type comes from a `.mime` file in the same directory, the
+//! file's extension, and then sniffing the first few bytes of the file.
+//! Results are cached per path, and redone whenever the file or the `.mime`
+//! file next to it is modified.
+//!
+//! A `.mime` file has one override per line, as `<name> <type>`, where the
+//! name is either a full file name, or an extension starting with a `.`:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! LICENSE text/plain; charset=utf-8
+//! .md text/markdown; charset=utf-8
+//! ```
+//!
+//! Full file names win over extensions, and types are used verbatim.  The
+//! `[mime]` table of a `.config` works the same way, and comes before all of
+//! these.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	path::{Path, PathBuf},
+	sync::{LazyLock, Mutex},
+	time::SystemTime,
+};
+
+use tokio::{fs, io::AsyncReadExt};
+
+/// name of the per-directory override file
+pub const OVERRIDE_FILE: &str = ".mime";
+
+const DEFAULT: &str = "application/octet-stream";
+const SNIFF_LEN: usize = 512;
+
+const EXTENSIONS: &[(&str, &str)] = &[
+	// text
+	("html", "text/html"),
+	("htm", "text/html"),
+	("css", "text/css"),
+	("csv", "text/csv"),
+	("txt", "text/plain"),
+	("md", "text/markdown"),
+	("xml", "text/xml"),
+	("js", "text/javascript"),
+	("mjs", "text/javascript"),
+	("json", "application/json"),
+	("map", "application/json"),
+	("webmanifest", "application/manifest+json"),
+	("svg", "image/svg+xml"),
+	// images
+	("png", "image/png"),
+	("jpg", "image/jpeg"),
+	("jpeg", "image/jpeg"),
+	("gif", "image/gif"),
+	("webp", "image/webp"),
+	("avif", "image/avif"),
+	("ico", "image/vnd.microsoft.icon"),
+	("bmp", "image/bmp"),
+	// audio and video
+	("mp3", "audio/mpeg"),
+	("ogg", "audio/ogg"),
+	("oga", "audio/ogg"),
+	("wav", "audio/wav"),
+	("flac", "audio/flac"),
+	("mp4", "video/mp4"),
+	("m4v", "video/mp4"),
+	("webm", "video/webm"),
+	("ogv", "video/ogg"),
+	// fonts
+	("woff", "font/woff"),
+	("woff2", "font/woff2"),
+	("ttf", "font/ttf"),
+	("otf", "font/otf"),
+	// documents and archives
+	("pdf", "application/pdf"),
+	("zip", "application/zip"),
+	("gz", "application/gzip"),
+	("tar", "application/x-tar"),
+	("wasm", "application/wasm"),
+];
+
+// magic numbers, checked against the start of a file
+const SIGNATURES: &[(&[u8], &str)] = &[
+	(b"\x89PNG\r\n\x1a\n", "image/png"),
+	(b"\xff\xd8\xff", "image/jpeg"),
+	(b"GIF87a", "image/gif"),
+	(b"GIF89a", "image/gif"),
+	(b"%PDF-", "application/pdf"),
+	(b"PK\x03\x04", "application/zip"),
+	(b"\x1f\x8b", "application/gzip"),
+	(b"\0asm", "application/wasm"),
+	(b"OggS", "application/ogg"),
+	(b"ID3", "audio/mpeg"),
+	(b"fLaC", "audio/flac"),
+	(b"\x1a\x45\xdf\xa3", "video/webm"),
+	(b"wOFF", "font/woff"),
+	(b"wOF2", "font/woff2"),
+];
+
+#[derive(Debug)]
+struct Cached {
+	// modification times of the file, and of the override file next to it
+	modified: (Option<SystemTime>, Option<SystemTime>),
+	mime: String,
+}
+
+static CACHE: LazyLock<Mutex<HashMap<PathBuf, Cached>>> = LazyLock::new(Default::default);
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+	fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+/// The `Content-Type` to serve a file with.  Sniffing the contents of the
+/// file can be turned off, in which case unknown files are just bytes.
+pub async fn mime_type(path: &Path, configured: &BTreeMap<String, String>, sniff: bool) -> String {
+	let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+	if let Some(mime) = matching(configured.iter().map(|(p, m)| (p.as_str(), m.as_str())), name) {
+		return mime;
+	}
+	let overrides = path.with_file_name(OVERRIDE_FILE);
+	let modified = (modified(path).await, modified(&overrides).await);
+	if let Some(cached) = CACHE.lock().unwrap().get(path)
+		&& cached.modified == modified
+	{
+		return cached.mime.clone();
+	}
+	let mime = resolve(path, &overrides, sniff).await;
+	CACHE.lock().unwrap().insert(
+		path.to_path_buf(),
+		Cached {
+			modified,
+			mime: mime.clone(),
+		},
+	);
+	mime
+}
+
+async fn resolve(path: &Path, overrides: &Path, sniff: bool) -> String {
+	let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+	if let Some(mime) = from_overrides(overrides, name).await {
+		return mime;
+	}
+	let head = if sniff { read_head(path).await } else { None };
+	let known = name
+		.rsplit_once(".")
+		.map(|(_, ext)| ext.to_ascii_lowercase())
+		.and_then(|ext| EXTENSIONS.iter().find(|(e, _)| *e == ext))
+		.map(|(_, mime)| *mime);
+	match (known, head) {
+		(Some(mime), head) if is_text(mime) => with_charset(mime, head.as_deref()),
+		(Some(mime), _) => mime.to_string(),
+		(None, Some(head)) => sniff_type(&head),
+		(None, None) => DEFAULT.to_string(),
+	}
+}
+
+async fn from_overrides(overrides: &Path, name: &str) -> Option<String> {
+	let content = fs::read_to_string(overrides).await.ok()?;
+	let entries = content
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with("#"))
+		.filter_map(|l| l.split_once(char::is_whitespace))
+		.map(|(pattern, mime)| (pattern, mime.trim()));
+	matching(entries, name)
+}
+
+// the type for a file name out of `(pattern, type)` overrides
+fn matching<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>, name: &str) -> Option<String> {
+	let entries = entries.collect::<Vec<_>>();
+	let lower = name.to_ascii_lowercase();
+	entries
+		.iter()
+		.find(|(pattern, _)| *pattern == name)
+		.or_else(|| entries.iter().find(|(pattern, _)|
+			pattern.starts_with(".")
+				&& lower.len() > pattern.len()
+				&& lower.ends_with(&pattern.to_ascii_lowercase())
+		))
+		.map(|(_, mime)| mime.to_string())
+}
+
+async fn read_head(path: &Path) -> Option<Vec<u8>> {
+	let file = fs::File::open(path).await.ok()?;
+	let mut head = Vec::with_capacity(SNIFF_LEN);
+	file.take(SNIFF_LEN as u64).read_to_end(&mut head).await.ok()?;
+	Some(head)
+}
+
+fn is_text(mime: &str) -> bool {
+	mime.starts_with("text/")
+		|| ["application/json", "application/manifest+json", "image/svg+xml"].contains(&mime)
+}
+
+fn is_utf8(head: &[u8]) -> bool {
+	match std::str::from_utf8(head) {
+		Ok(_) => true,
+		// the sniffed bytes may have cut a character in half
+		Err(e) => e.error_len().is_none() && head.len() == SNIFF_LEN,
+	}
+}
+
+// without anything to go off of, assumes text is utf-8
+fn with_charset(mime: &str, head: Option<&[u8]>) -> String {
+	if head.is_none_or(is_utf8) {
+		format!("{}; charset=utf-8", mime)
+	} else {
+		mime.to_string()
+	}
+}
+
+fn sniff_type(head: &[u8]) -> String {
+	if let Some((_, mime)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) {
+		return mime.to_string();
+	}
+	if head.len() >= 12 && head.starts_with(b"RIFF") {
+		match &head[8..12] {
+			b"WEBP" => return "image/webp".to_string(),
+			b"WAVE" => return "audio/wav".to_string(),
+			_ => {}
+		}
+	}
+	if head.len() >= 8 && &head[4..8] == b"ftyp" {
+		return "video/mp4".to_string();
+	}
+	if head.contains(&0) || !is_utf8(head) {
+		return DEFAULT.to_string();
+	}
+	let start = head.trim_ascii_start().to_ascii_lowercase();
+	if start.starts_with(b"<!doctype html") || start.starts_with(b"<html") {
+		with_charset("text/html", Some(head))
+	} else if start.starts_with(b"<?xml") {
+		with_charset("text/xml", Some(head))
+	} else {
+		with_charset("text/plain", Some(head))
+	}
+}
@@ -0,0 +1,186 @@
+//! Reverse proxying, for paths that are served by another HTTP service.
+//!
+//! A `.proxy` file has the URL of the upstream, and the directory it's in and
+//! everything under it is forwarded there, with the rest of the path added
+//! to the URL and the query kept as it is:
+//!
+//! ```text
+//! http://127.0.0.1:3000/api
+//! ```
+//!
+//! The upstream is told where the request came from with `X-Forwarded-For`,
+//! `X-Forwarded-Proto` and `X-Forwarded-Host`.  Hop-by-hop headers aren't
+//! passed on either way, except for the ones a WebSocket (or any other HTTP/1
+//! upgrade) needs, which is then connected straight through once the upstream
+//! switches protocols.  The `timeout` of the directory covers getting the
+//! response headers, anything longer is a 504.
+
+use std::sync::LazyLock;
+
+use http::{
+	header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, UPGRADE},
+	request::Parts,
+	Method, Request, Response,
+};
+use hyper::{body::Incoming, upgrade::OnUpgrade};
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+	client::legacy::{connect::HttpConnector, Client},
+	rt::{TokioExecutor, TokioIo},
+};
+
+use crate::{cgi::Peer, decode, log, rewrite, serve::ResponseBody};
+
+/// name of the file naming an upstream
+pub const PROXY_FILE: &str = ".proxy";
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+// only for this connection, not to be passed on
+const HOP_BY_HOP: &[&str] = &[
+	"connection",
+	"keep-alive",
+	"proxy-connection",
+	"proxy-authenticate",
+	"proxy-authorization",
+	"te",
+	"trailer",
+	"transfer-encoding",
+	"upgrade",
+];
+
+// shared, so connections to upstreams are reused
+static CLIENT: LazyLock<Client<HttpsConnector<HttpConnector>, ResponseBody>> =
+	LazyLock::new(|| Client::builder(TokioExecutor::new()).build(HttpsConnector::new()));
+
+/// The URL in a `.proxy` file.
+pub fn parse_upstream(content: &str) -> Option<&str> {
+	rewrite::first_line(content)
+}
+
+/// Where a request goes upstream, with the rest of the path and the query.
+pub fn upstream_uri(upstream: &str, remaining: &[String], req: &Parts) -> String {
+	let mut uri = upstream.trim_end_matches("/").to_string();
+	for segment in remaining {
+		uri.push('/');
+		uri.push_str(&decode::percent_encode(segment));
+	}
+	if req.uri.path().ends_with("/") && !remaining.is_empty() {
+		uri.push('/');
+	}
+	if let Some(query) = req.uri.query() {
+		uri.push('?');
+		uri.push_str(query);
+	}
+	uri
+}
+
+fn is_upgrade(headers: &HeaderMap) -> bool {
+	headers.contains_key(UPGRADE)
+		&& headers
+			.get_all(CONNECTION)
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(|v| v.split(","))
+			.any(|v| v.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+// the headers to pass on, without hop-by-hop ones and the ones `Connection` names
+fn end_to_end(headers: &HeaderMap) -> HeaderMap {
+	let named = headers
+		.get_all(CONNECTION)
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(","))
+		.map(|v| v.trim().to_ascii_lowercase())
+		.collect::<Vec<String>>();
+	let mut kept = headers.clone();
+	for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+		kept.remove(name);
+	}
+	kept
+}
+
+/// Forwards a request upstream, and answers with the status and headers of
+/// the response.  If the upstream switched protocols, the client connection
+/// is connected to it from then on.
+pub async fn forward(
+	uri: &str,
+	req: &Parts,
+	peer: &Peer,
+	body: ResponseBody,
+	len: Option<u64>,
+	upgrade: Option<OnUpgrade>,
+) -> Result<Response<Incoming>, String> {
+	let mut headers = end_to_end(&req.headers);
+	// the client sets the upstream's own
+	headers.remove(HOST);
+	// a buffered body is always there, even when the client didn't send one
+	let sent_body = headers.remove(CONTENT_LENGTH).is_some();
+	if let Some(len) = len.filter(|l| *l > 0 || sent_body) {
+		headers.insert(CONTENT_LENGTH, len.into());
+	}
+	let forwarded_for = match req.headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+		Some(earlier) => format!("{}, {}", earlier, peer.remote.ip()),
+		None => peer.remote.ip().to_string(),
+	};
+	let forwarded = [
+		(X_FORWARDED_FOR, Some(forwarded_for)),
+		(X_FORWARDED_PROTO, Some((if peer.secure { "https" } else { "http" }).to_string())),
+		(
+			X_FORWARDED_HOST,
+			req.uri
+				.authority()
+				.map(|a| a.to_string())
+				.or_else(|| req.headers.get(HOST).and_then(|h| h.to_str().ok()).map(String::from)),
+		),
+	];
+	for (name, value) in forwarded {
+		if let Some(value) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+			headers.insert(name, value);
+		}
+	}
+	let upgrade = upgrade.filter(|_| is_upgrade(&req.headers));
+	if let (Some(_), Some(protocol)) = (&upgrade, req.headers.get(UPGRADE)) {
+		headers.insert(UPGRADE, protocol.clone());
+		headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+	}
+
+	let mut request = Request::builder()
+		.method(req.method.clone())
+		.uri(uri)
+		.body(body)
+		.map_err(|e| format!("bad upstream request: {}", e))?;
+	*request.headers_mut() = headers;
+	let mut response = CLIENT
+		.request(request)
+		.await
+		.map_err(|e| format!("could not reach {}: {}", uri, e))?;
+
+	if let Some(client) = upgrade.filter(|_| response.status().as_u16() == 101) {
+		let upstream = hyper::upgrade::on(&mut response);
+		let uri = uri.to_string();
+		tokio::spawn(async move {
+			match tokio::try_join!(client, upstream) {
+				Ok((client, upstream)) => {
+					let _ = tokio::io::copy_bidirectional(
+						&mut TokioIo::new(client),
+						&mut TokioIo::new(upstream)
+					).await;
+				}
+				Err(e) => log!(error "PROXY"; "Could not upgrade connection to {}: {}", uri, e),
+			}
+		});
+		return Ok(response);
+	}
+	let mut kept = end_to_end(response.headers());
+	// it's counted again on the way out, except for HEAD, which has no body
+	// to count
+	if req.method != Method::HEAD {
+		kept.remove(CONTENT_LENGTH);
+	}
+	*response.headers_mut() = kept;
+	Ok(response)
+}
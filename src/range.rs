@@ -0,0 +1,204 @@
+//! `Range` requests for static files (RFC 9110, section 14).
+
+use std::{ops::RangeInclusive, time::SystemTime};
+
+use hyper::header::{HeaderMap, IF_RANGE, RANGE};
+
+use crate::conditional;
+
+// more than this and it's cheaper to just send the whole file
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug)]
+pub enum Ranges {
+	Full,
+	Partial(Vec<RangeInclusive<u64>>),
+	Unsatisfiable,
+}
+
+/// What part of a file of the given length the request asks for.  Anything
+/// malformed, or a stale `If-Range`, means the whole file.
+pub fn requested(
+	headers: &HeaderMap,
+	len: u64,
+	etag: Option<&str>,
+	modified: Option<SystemTime>
+) -> Ranges {
+	let Some(range) = headers.get(RANGE).and_then(|r| r.to_str().ok()) else {
+		return Ranges::Full;
+	};
+	if let Some(validator) = headers.get(IF_RANGE)
+		&& !validator.to_str().is_ok_and(|v| conditional::if_range_matches(v, etag, modified))
+	{
+		return Ranges::Full;
+	}
+	parse(range, len).unwrap_or(Ranges::Full)
+}
+
+fn parse(header: &str, len: u64) -> Option<Ranges> {
+	let specs = header.trim().strip_prefix("bytes=")?;
+	let mut ranges = Vec::new();
+	let mut any = false;
+	for spec in specs.split(",").map(str::trim).filter(|s| !s.is_empty()) {
+		any = true;
+		let (start, end) = spec.split_once("-")?;
+		if start.is_empty() {
+			// suffix range, the last n bytes
+			let suffix: u64 = end.trim().parse().ok()?;
+			if suffix > 0 && len > 0 {
+				ranges.push(len.saturating_sub(suffix)..=len - 1);
+			}
+			continue;
+		}
+		let start: u64 = start.trim().parse().ok()?;
+		let end: u64 = if end.trim().is_empty() {
+			u64::MAX
+		} else {
+			end.trim().parse().ok()?
+		};
+		if end < start {
+			return None;
+		}
+		if start < len {
+			ranges.push(start..=end.min(len - 1));
+		}
+	}
+	if !any || ranges.len() > MAX_RANGES {
+		return None;
+	}
+	if ranges.is_empty() {
+		return Some(Ranges::Unsatisfiable);
+	}
+	Some(Ranges::Partial(coalesce(ranges)))
+}
+
+// overlapping and adjacent ranges get merged, so nothing is sent twice
+fn coalesce(mut ranges: Vec<RangeInclusive<u64>>) -> Vec<RangeInclusive<u64>> {
+	ranges.sort_by_key(|r| *r.start());
+	let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+	for range in ranges {
+		match merged.last_mut() {
+			Some(last) if *range.start() <= last.end().saturating_add(1) => {
+				*last = *last.start()..=*range.end().max(last.end());
+			}
+			_ => merged.push(range),
+		}
+	}
+	merged
+}
+
+pub fn content_range(range: &RangeInclusive<u64>, len: u64) -> String {
+	format!("bytes {}-{}/{}", range.start(), range.end(), len)
+}
+
+/// A boundary for `multipart/byteranges` that won't show up in the content
+/// by accident.
+pub fn boundary() -> String {
+	let nanos = SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map(|d| d.as_nanos())
+		.unwrap_or_default();
+	format!("simple_serve_{:x}", nanos)
+}
+
+/// The delimiter and headers in front of one part of a multipart response.
+pub fn part_header(boundary: &str, mimetype: &str, range: &RangeInclusive<u64>, len: u64) -> String {
+	format!(
+		"\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+		boundary,
+		mimetype,
+		content_range(range, len)
+	)
+}
+
+pub fn closing_boundary(boundary: &str) -> String {
+	format!("\r\n--{}--\r\n", boundary)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use hyper::header::HeaderValue;
+
+	use super::*;
+
+	fn ranges(header: &str, len: u64) -> Option<Vec<RangeInclusive<u64>>> {
+		match parse(header, len)? {
+			Ranges::Partial(ranges) => Some(ranges),
+			other => panic!("{:?} for {:?}", other, header),
+		}
+	}
+
+	fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.insert(*name, HeaderValue::from_str(value).unwrap());
+		}
+		headers
+	}
+
+	#[test]
+	fn single() {
+		assert_eq!(ranges("bytes=0-4", 10), Some(vec![0..=4]));
+		assert_eq!(ranges("bytes=5-100", 10), Some(vec![5..=9]));
+		assert_eq!(ranges(" bytes= 2 - 3 ", 10), Some(vec![2..=3]));
+	}
+
+	#[test]
+	fn open_ended() {
+		assert_eq!(ranges("bytes=7-", 10), Some(vec![7..=9]));
+		assert_eq!(ranges("bytes=0-", 1), Some(vec![0..=0]));
+	}
+
+	#[test]
+	fn suffix() {
+		assert_eq!(ranges("bytes=-3", 10), Some(vec![7..=9]));
+		assert_eq!(ranges("bytes=-30", 10), Some(vec![0..=9]));
+	}
+
+	#[test]
+	fn malformed() {
+		for header in ["0-4", "bytes=", "bytes=a-4", "bytes=4-a", "bytes=5-4", "bytes=--1", "bits=0-4", "bytes=4"] {
+			assert!(parse(header, 10).is_none(), "{}", header);
+		}
+		let many = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect::<Vec<_>>();
+		assert!(parse(&format!("bytes={}", many.join(",")), 1000).is_none());
+	}
+
+	#[test]
+	fn unsatisfiable() {
+		for (header, len) in [("bytes=10-", 10), ("bytes=20-30", 10), ("bytes=-0", 10), ("bytes=-5", 0), ("bytes=0-", 0)] {
+			assert!(matches!(parse(header, len), Some(Ranges::Unsatisfiable)), "{} of {}", header, len);
+		}
+		// one that's satisfiable is enough
+		assert_eq!(ranges("bytes=20-30,0-1", 10), Some(vec![0..=1]));
+	}
+
+	#[test]
+	fn coalescing() {
+		assert_eq!(ranges("bytes=0-1,2-3", 10), Some(vec![0..=3]));
+		assert_eq!(ranges("bytes=5-8,0-2,1-6", 10), Some(vec![0..=8]));
+		assert_eq!(ranges("bytes=6-7,0-1", 10), Some(vec![0..=1, 6..=7]));
+		assert_eq!(ranges("bytes=0-0,-1", 10), Some(vec![0..=0, 9..=9]));
+		assert_eq!(ranges("bytes=0-,-5", 10), Some(vec![0..=9]));
+	}
+
+	#[test]
+	fn if_range() {
+		let etag = "\"abc\"";
+		let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+		let date = conditional::http_date(modified);
+		let partial = |h: &HeaderMap| matches!(requested(h, 10, Some(etag), Some(modified)), Ranges::Partial(_));
+
+		assert!(partial(&headers(&[("range", "bytes=0-1")])));
+		assert!(partial(&headers(&[("range", "bytes=0-1"), ("if-range", etag)])));
+		assert!(partial(&headers(&[("range", "bytes=0-1"), ("if-range", &date)])));
+		// stale, or only a weak match, gets the whole file
+		assert!(!partial(&headers(&[("range", "bytes=0-1"), ("if-range", "\"other\"")])));
+		assert!(!partial(&headers(&[("range", "bytes=0-1"), ("if-range", "W/\"abc\"")])));
+		let later = conditional::http_date(modified + Duration::from_secs(60));
+		assert!(!partial(&headers(&[("range", "bytes=0-1"), ("if-range", &later)])));
+		assert!(matches!(requested(&headers(&[]), 10, Some(etag), Some(modified)), Ranges::Full));
+	}
+}
@@ -0,0 +1,148 @@
+//! `.redirect` and `.alias` files, which send a directory and everything
+//! under it somewhere else.
+//!
+//! A `.redirect` has the URL to redirect to, and optionally a status, which
+//! has to be a 3xx and defaults to 302.  When the URL ends in `/`, the rest
+//! of the requested path is added to it:
+//!
+//! ```text
+//! https://example.com/new/ 301
+//! ```
+//!
+//! An `.alias` has a path from the top of the served folder, which is served
+//! in place of the directory without the client ever knowing.  The rest of
+//! the requested path is always added to it.
+//!
+//! Both can use what regex directories captured on the way there: `$n` is
+//! the n-th path parameter, counting from 0 the same as executables get them,
+//! and `$name` or `${name}` is a named group.  `$$` is a plain `$`.
+
+use crate::decode;
+
+/// name of the redirect file
+pub const REDIRECT_FILE: &str = ".redirect";
+/// name of the alias file
+pub const ALIAS_FILE: &str = ".alias";
+
+/// The first line that isn't empty or a comment.
+pub fn first_line(content: &str) -> Option<&str> {
+	content
+		.lines()
+		.map(str::trim)
+		.find(|l| !l.is_empty() && !l.starts_with("#"))
+}
+
+/// Fills in captures from regex directories.  Anything that wasn't captured
+/// is left empty.
+pub fn substitute(template: &str, path_params: &[String], named: &[(String, String)]) -> String {
+	let lookup = |name: &str| match name.parse::<usize>() {
+		Ok(i) => path_params.get(i).cloned(),
+		// later directories shadow earlier ones
+		Err(_) => named.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v.clone()),
+	};
+	let mut out = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(at) = rest.find("$") {
+		out.push_str(&rest[..at]);
+		rest = &rest[at + 1..];
+		if let Some(after) = rest.strip_prefix("$") {
+			out.push('$');
+			rest = after;
+		} else if let Some((name, after)) = rest.strip_prefix("{").and_then(|r| r.split_once("}")) {
+			out.push_str(&lookup(name).unwrap_or_default());
+			rest = after;
+		} else {
+			let len = rest
+				.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+				.unwrap_or(rest.len());
+			if len == 0 {
+				out.push('$');
+			} else {
+				out.push_str(&lookup(&rest[..len]).unwrap_or_default());
+			}
+			rest = &rest[len..];
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+/// The target and status of a `.redirect`, before substitution.
+pub fn parse_redirect(content: &str) -> Result<(String, u16), String> {
+	let mut parts = first_line(content)
+		.ok_or("no target to redirect to")?
+		.split_whitespace();
+	let target = parts.next().ok_or("no target to redirect to")?.to_string();
+	let status = match parts.next() {
+		Some(status) => status
+			.parse::<u16>()
+			.ok()
+			.filter(|s| (300..400).contains(s))
+			.ok_or(format!("{} is not a redirect status", status))?,
+		None => 302,
+	};
+	Ok((target, status))
+}
+
+/// Where a redirect goes, with the rest of the path if it takes it.
+pub fn redirect_location(target: &str, remaining: &[String]) -> String {
+	if target.ends_with("/") && !remaining.is_empty() {
+		let rest = remaining.iter().map(|s| decode::percent_encode(s)).collect::<Vec<String>>();
+		format!("{}{}", target, rest.join("/"))
+	} else {
+		target.to_string()
+	}
+}
+
+/// The path segments an `.alias` points to, before the rest of the path.
+pub fn parse_alias(content: &str) -> Option<&str> {
+	first_line(content)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn strings(items: &[&str]) -> Vec<String> {
+		items.iter().map(|s| s.to_string()).collect()
+	}
+
+	#[test]
+	fn captures() {
+		let params = strings(&["bob", "42"]);
+		let named = [("user", "bob"), ("id", "7"), ("id", "42")].map(|(n, v)| (n.to_string(), v.to_string()));
+		assert_eq!(substitute("/users/$0/posts/$1", &params, &named), "/users/bob/posts/42");
+		// the last directory to capture a name wins
+		assert_eq!(substitute("/u/$user/$id", &params, &named), "/u/bob/42");
+		assert_eq!(substitute("/u/${user}s/x${id}y", &params, &named), "/u/bobs/x42y");
+		// names end at anything that can't be in one
+		assert_eq!(substitute("$user.html", &params, &named), "bob.html");
+		assert_eq!(substitute("$missing/$9/${nope}", &params, &named), "//");
+	}
+
+	#[test]
+	fn escaping() {
+		let params = strings(&["bob"]);
+		assert_eq!(substitute("$$0 costs $$", &params, &[]), "$0 costs $");
+		assert_eq!(substitute("$$$0", &params, &[]), "$bob");
+		// a `$` that can't start a name stays as it is
+		assert_eq!(substitute("a $ b $/", &params, &[]), "a $ b $/");
+		assert_eq!(substitute("end$", &params, &[]), "end$");
+		// an unclosed brace isn't a name
+		assert_eq!(substitute("${0", &params, &[]), "${0");
+	}
+
+	#[test]
+	fn redirects() {
+		let (target, status) = parse_redirect("# moved\n\nhttps://example.com/ 301\n").unwrap();
+		assert_eq!((target.as_str(), status), ("https://example.com/", 301));
+		assert_eq!(parse_redirect("/new").unwrap(), ("/new".to_string(), 302));
+		assert!(parse_redirect("/new 200").is_err());
+		assert!(parse_redirect("/new soon").is_err());
+		assert!(parse_redirect("# nothing\n").is_err());
+		let rest = strings(&["a b", "c"]);
+		assert_eq!(redirect_location("/new/", &rest), "/new/a%20b/c");
+		assert_eq!(redirect_location("/new", &rest), "/new");
+		assert_eq!(redirect_location("/new/", &[]), "/new/");
+	}
+}
@@ -0,0 +1,387 @@
+//! The route tree: what's in each served directory, and how path segments
+//! are matched against it.
+//!
+//! A directory named `&<regex>` matches a path segment when the regex matches
+//! all of it, as if it were written `^(?:<regex>)$`.  Plain names always win
+//! over regexes.  When more than one regex in a directory matches, the one
+//! listed first in that directory's `.order` file wins:
+//!
+//! ```text
+//! # one name per line, as it appears in the directory
+//! &new
+//! &[a-z]+
+//! ```
+//!
+//! Regexes that aren't listed are tried after the listed ones, sorted by name.
+//! Since it's easy to miss two regexes overlapping, the tree is checked once
+//! at startup, with a warning for every directory that leaves more than one
+//! regex unordered.
+//!
+//! Directories are read once, with their regexes compiled and their `.config`
+//! parsed, and kept in memory until the filesystem watcher sees something in
+//! them change.  What they list also answers whether a path exists at all,
+//! so missing files and probes for hooks don't go to the disk either.  If the
+//! served folder can't be watched, every lookup goes to the disk instead.
+
+use std::{
+	collections::{HashMap, HashSet},
+	fs as std_fs,
+	io,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, LazyLock, Mutex,
+	},
+};
+
+use notify::{
+	event::{EventKind, ModifyKind},
+	Event, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use regex::Regex;
+use tokio::task::spawn_blocking;
+
+use crate::{
+	log,
+	settings::{Settings, CONFIG_FILE},
+};
+
+/// name of the per-directory priority file
+pub const ORDER_FILE: &str = ".order";
+
+/// One directory of the served tree.
+#[derive(Debug)]
+pub struct Dir {
+	entries: HashSet<String>,
+	/// regex directories, highest priority first, with the regexes that
+	/// compiled
+	pub regexes: Vec<(String, Regex)>,
+	/// what the directory's `.config` sets, not counting what it inherits
+	pub settings: Settings,
+	// entries that are directories themselves, not counting symlinks
+	subdirs: Vec<String>,
+	// symlinks can lead out of the watched tree, so what's behind them
+	// can't be cached
+	links: HashSet<String>,
+}
+
+impl Dir {
+	pub fn has(&self, name: &str) -> bool {
+		self.entries.contains(name)
+	}
+
+	pub fn entries(&self) -> impl Iterator<Item = &str> {
+		self.entries.iter().map(String::as_str)
+	}
+}
+
+static CACHE: LazyLock<Mutex<HashMap<PathBuf, Arc<Dir>>>> = LazyLock::new(Default::default);
+// roots that are being watched, everything cached is under one of them
+static WATCHED: LazyLock<Mutex<Vec<PathBuf>>> = LazyLock::new(Default::default);
+// bumped on every change, so a directory read while something changed in it
+// doesn't get cached
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The regex of a regex directory, anchored to the whole segment.  `None` for
+/// other names, and for regexes that don't compile.
+pub fn compile(name: &str) -> Option<Regex> {
+	let pattern = name.strip_prefix("&")?;
+	Regex::new(&format!("^(?:{})$", pattern)).ok()
+}
+
+fn parse_order(content: &str) -> Vec<String> {
+	content
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with("#"))
+		.map(String::from)
+		.collect()
+}
+
+fn read_order(dir: &Path) -> Vec<String> {
+	std_fs::read_to_string(dir.join(ORDER_FILE))
+		.map(|c| parse_order(&c))
+		.unwrap_or_default()
+}
+
+// listed names first, in the order they're listed, then the rest by name
+fn sorted<'a>(entries: impl Iterator<Item = &'a String>, order: &[String]) -> Vec<String> {
+	let mut regexes = entries
+		.filter(|e| e.starts_with("&"))
+		.cloned()
+		.collect::<Vec<String>>();
+	regexes.sort_by_cached_key(|r| (order.iter().position(|o| o == r).unwrap_or(usize::MAX), r.clone()));
+	regexes
+}
+
+fn read(dir: &Path) -> io::Result<Dir> {
+	let mut entries = HashSet::new();
+	let mut subdirs = Vec::new();
+	let mut links = HashSet::new();
+	for entry in std_fs::read_dir(dir)? {
+		let entry = entry?;
+		let Some(name) = entry.file_name().to_str().map(String::from) else {
+			continue;
+		};
+		let file_type = entry.file_type()?;
+		if file_type.is_symlink() {
+			links.insert(name.clone());
+		} else if file_type.is_dir() {
+			subdirs.push(name.clone());
+		}
+		entries.insert(name);
+	}
+	subdirs.sort();
+	let regexes = sorted(entries.iter(), &read_order(dir))
+		.into_iter()
+		.filter_map(|name| compile(&name).map(|re| (name, re)))
+		.collect();
+	// a broken config is reported, and then acts like it isn't there
+	let settings = match std_fs::read_to_string(dir.join(CONFIG_FILE)) {
+		Ok(content) => Settings::parse(&content).map(|s| s.anchor(dir)).unwrap_or_else(|e| {
+			log!(error "CONFIG"; "{}: {}", dir.join(CONFIG_FILE).display(), e);
+			Settings::default()
+		}),
+		Err(_) => Settings::default(),
+	};
+	Ok(Dir {
+		entries,
+		regexes,
+		settings,
+		subdirs,
+		links,
+	})
+}
+
+// only directories reached without going through a symlink get cached
+fn cacheable(dir: &Path, cache: &HashMap<PathBuf, Arc<Dir>>) -> bool {
+	if WATCHED.lock().unwrap().iter().any(|root| root == dir) {
+		return true;
+	}
+	let (Some(parent), Some(name)) = (dir.parent(), dir.file_name().and_then(|n| n.to_str())) else {
+		return false;
+	};
+	cache.get(parent).is_some_and(|p| p.subdirs.iter().any(|d| d == name) && !p.links.contains(name))
+}
+
+/// What a path is, as far as the tree in memory knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+	Missing,
+	File,
+	Dir,
+}
+
+/// What a path is, from the closest directory above it that's cached.
+/// `None` when only the disk can tell, like behind a symlink or in a
+/// directory that hasn't been read yet.
+pub fn known(path: &Path) -> Option<Entry> {
+	let cache = CACHE.lock().unwrap();
+	let (above, dir) = path.ancestors().skip(1).find_map(|a| Some((a, cache.get(a)?)))?;
+	let mut rest = path.strip_prefix(above).ok()?.iter();
+	let name = rest.next()?.to_str()?;
+	if dir.links.contains(name) {
+		return None;
+	}
+	let is_dir = dir.subdirs.iter().any(|d| d == name);
+	match (dir.has(name), is_dir, rest.next().is_none()) {
+		(false, _, _) => Some(Entry::Missing),
+		(true, true, true) => Some(Entry::Dir),
+		(true, false, true) => Some(Entry::File),
+		// nothing is under a file
+		(true, false, false) => Some(Entry::Missing),
+		// a directory that isn't cached itself
+		(true, true, false) => None,
+	}
+}
+
+/// What's in a directory, from memory when it hasn't changed since it was
+/// last read.  `None` if it isn't a readable directory.
+pub async fn lookup(dir: &Path) -> Option<Arc<Dir>> {
+	if let Some(cached) = CACHE.lock().unwrap().get(dir) {
+		return Some(cached.clone());
+	}
+	if matches!(known(dir), Some(Entry::Missing | Entry::File)) {
+		return None;
+	}
+	let generation = GENERATION.load(Ordering::Acquire);
+	let path = dir.to_path_buf();
+	let read = Arc::new(spawn_blocking(move || read(&path)).await.ok()?.ok()?);
+	let mut cache = CACHE.lock().unwrap();
+	if GENERATION.load(Ordering::Acquire) == generation && cacheable(dir, &cache) {
+		cache.insert(dir.to_path_buf(), read.clone());
+	}
+	Some(read)
+}
+
+fn invalidate(paths: &[PathBuf]) {
+	GENERATION.fetch_add(1, Ordering::AcqRel);
+	let mut cache = CACHE.lock().unwrap();
+	for path in paths {
+		cache.retain(|dir, _| !dir.starts_with(path) && Some(dir.as_path()) != path.parent());
+	}
+}
+
+fn handle_event(event: notify::Result<Event>) {
+	let event = match event {
+		Ok(event) => event,
+		Err(e) => {
+			log!(error "ROUTES"; "Error watching served folder, dropping route cache: {}", e);
+			GENERATION.fetch_add(1, Ordering::AcqRel);
+			CACHE.lock().unwrap().clear();
+			return;
+		}
+	};
+	if event.need_rescan() {
+		GENERATION.fetch_add(1, Ordering::AcqRel);
+		CACHE.lock().unwrap().clear();
+		return;
+	}
+	match event.kind {
+		// files being served and read
+		EventKind::Access(_) => {}
+		// contents don't matter for routing, except for the order and settings
+		EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_)) => {
+			let read = event
+				.paths
+				.iter()
+				.filter(|p| p.file_name().is_some_and(|n| n == ORDER_FILE || n == CONFIG_FILE))
+				.cloned()
+				.collect::<Vec<PathBuf>>();
+			invalidate(&read);
+		}
+		_ => invalidate(&event.paths),
+	}
+}
+
+/// Starts watching a served folder for changes, so its directories can be
+/// cached.  The cache only lives as long as the returned watcher.
+pub fn watch(base: &Path) -> Option<RecommendedWatcher> {
+	let watcher = notify::recommended_watcher(handle_event).and_then(|mut w| {
+		w.watch(base, RecursiveMode::Recursive)?;
+		Ok(w)
+	});
+	match watcher {
+		Ok(watcher) => {
+			WATCHED.lock().unwrap().push(base.to_path_buf());
+			Some(watcher)
+		}
+		Err(e) => {
+			log!(error "ROUTES"; "Could not watch {}, routes won't be cached: {}", base.display(), e);
+			None
+		}
+	}
+}
+
+/// Walks the tree under `base`, filling the cache and warning about regex
+/// directories that can never match, `.order` entries that don't exist, and
+/// directories where the priority between regexes is left to their names.
+pub fn check(base: &Path) {
+	let Ok(dir) = read(base) else {
+		return;
+	};
+	let order = read_order(base);
+	for name in order.iter().filter(|o| !dir.has(o)) {
+		log!(error "ROUTES"; "{} lists {}, which doesn't exist", base.join(ORDER_FILE).display(), name);
+	}
+	let regexes = sorted(dir.entries.iter(), &order);
+	for name in regexes.iter().filter(|r| compile(r).is_none()) {
+		log!(error "ROUTES"; "{} is not a valid regex, and will never match", base.join(name).display());
+	}
+	let unordered = dir
+		.regexes
+		.iter()
+		.map(|(name, _)| name.as_str())
+		.filter(|name| !order.iter().any(|o| o == name))
+		.collect::<Vec<&str>>();
+	if unordered.len() > 1 {
+		log!(error "ROUTES";
+			"{} has regex directories that may overlap, tried by name: {}.  List them in {} to fix their order.",
+			base.display(),
+			unordered.join(", "),
+			ORDER_FILE
+		);
+	}
+	let dir = Arc::new(dir);
+	{
+		let mut cache = CACHE.lock().unwrap();
+		if cacheable(base, &cache) {
+			cache.insert(base.to_path_buf(), dir.clone());
+		}
+	}
+	// hidden directories are never routed into
+	for sub in dir.subdirs.iter().filter(|d| !d.starts_with(".")) {
+		check(&base.join(sub));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	#[test]
+	fn order() {
+		let order = parse_order("# first\n&b\n\n  &a  \n");
+		assert_eq!(order, ["&b", "&a"]);
+		let entries = ["&c", "&a", "&b", "plain"].map(String::from);
+		assert_eq!(sorted(entries.iter(), &order), ["&b", "&a", "&c"]);
+	}
+
+	#[test]
+	fn anchored() {
+		let re = compile("&[a-z]+").unwrap();
+		assert!(re.is_match("abc"));
+		assert!(!re.is_match("abc1"));
+		assert!(!re.is_match("1abc"));
+		// alternations don't get around the anchors
+		let re = compile("&a|b").unwrap();
+		assert!(!re.is_match("ab"));
+		assert!(!re.is_match("xb"));
+		assert!(compile("plain").is_none());
+		assert!(compile("&(").is_none());
+	}
+
+	// changes reach the cache once the watcher has seen them, and the
+	// directory is read again
+	async fn eventually(path: &Path, entry: Entry) -> bool {
+		for _ in 0..250 {
+			lookup(path.parent().unwrap()).await;
+			if known(path) == Some(entry) {
+				return true;
+			}
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+		false
+	}
+
+	#[tokio::test]
+	async fn cached_until_changed() {
+		let base = tempfile::tempdir().unwrap();
+		let base = base.path();
+		std_fs::create_dir(base.join("sub")).unwrap();
+		std_fs::write(base.join("sub/file"), "").unwrap();
+		let _watcher = watch(base).unwrap();
+		check(base);
+
+		// answered from memory, without reading anything
+		assert_eq!(known(&base.join("sub")), Some(Entry::Dir));
+		assert_eq!(known(&base.join("sub/file")), Some(Entry::File));
+		assert_eq!(known(&base.join("sub/missing")), Some(Entry::Missing));
+		assert_eq!(known(&base.join("sub/file/deeper")), Some(Entry::Missing));
+		assert_eq!(known(&base.join("missing/.error/404")), Some(Entry::Missing));
+		let first = lookup(&base.join("sub")).await.unwrap();
+		assert!(Arc::ptr_eq(&first, &lookup(&base.join("sub")).await.unwrap()));
+		assert!(lookup(&base.join("sub/file")).await.is_none());
+
+		std_fs::write(base.join("sub/new"), "").unwrap();
+		assert!(eventually(&base.join("sub/new"), Entry::File).await);
+		let changed = lookup(&base.join("sub")).await.unwrap();
+		assert!(!Arc::ptr_eq(&first, &changed));
+		assert!(changed.has("new"));
+
+		std_fs::remove_file(base.join("sub/file")).unwrap();
+		assert!(eventually(&base.join("sub/file"), Entry::Missing).await);
+	}
+}
@@ -1,20 +1,59 @@
-use http::{Error, response::Builder};
-use http_body_util::{BodyExt, Full};
-use regex::Regex;
+// `Result` is used for control flow over `ProcessingState`s (see `BackTrackState`),
+// not just for errors, so its `Err` variant is expected to be large.
+#![allow(clippy::result_large_err)]
+
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use http::{
+	Error, Method,
+	header::{HeaderMap, HeaderName, HeaderValue, ALLOW, CONTENT_LENGTH, LOCATION},
+	request::Parts,
+	response::Builder,
+};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full, StreamBody};
 use hyper::{
 	Request, Response,
-	body::{Body, Bytes, Incoming},
+	body::{Body, Bytes, Frame, Incoming},
+	upgrade::OnUpgrade,
 };
 use std::{
-	fs::{read_dir, DirEntry, File},
-	io::{Read, Seek, Write},
+	convert::Infallible,
+	future::Future,
+	io::{self, SeekFrom},
+	ops::RangeInclusive,
+	os::unix::fs::PermissionsExt,
 	path::{Path, PathBuf},
-	process::{Child, Command, Stdio},
+	process::Stdio,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, SystemTime},
 };
+use tokio::{
+	fs::{self, File},
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+	process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+	task::spawn_blocking,
+	time::Instant,
+};
+use tokio_util::io::ReaderStream;
 
-use is_executable::IsExecutable;
-
-use cmd_lib::run_fun;
+use crate::{
+	cgi::{self, Peer},
+	compress::{self, Encoding},
+	conditional,
+	decode,
+	fastcgi,
+	listing,
+	mime,
+	proxy,
+	range::{self, Ranges},
+	rewrite,
+	routes,
+	settings::{self, Settings, Workers},
+	vhost::{self, Hosts},
+	workers,
+};
 
 use tempfile::tempfile;
 
@@ -48,12 +87,192 @@ use tempfile::tempfile;
     };
 }
 
+/// Server-wide settings for how requests are handled.
+#[derive(Debug)]
+pub struct Config {
+	/// Pipe request bodies into the first executable as they arrive, instead
+	/// of buffering them to a tempfile before routing.
+	pub stream_request_body: bool,
+	/// Look at the contents of static files to figure out their type, when
+	/// the extension isn't enough.
+	pub sniff_mime: bool,
+	/// Give static files strong entity tags, hashed from their contents,
+	/// instead of weak ones made from their size and modification time.
+	pub strong_etags: bool,
+	/// Compress responses when the client accepts it.
+	pub compress: bool,
+	/// Bodies known to be smaller than this many bytes aren't compressed.
+	pub compress_min_size: u64,
+	/// `Content-Type`s to compress, entries ending in `/*` cover the whole
+	/// top level type.
+	pub compress_types: Vec<String>,
+	/// Which folder each host is served from.
+	pub hosts: Hosts,
+	/// How long handlers can run for, when their `.config` doesn't say.
+	pub timeout: Option<Duration>,
+}
+
+/// Everything about a request that stays the same while it is routed.
+struct Context<'a> {
+	req: &'a Parts,
+	config: &'a Config,
+	// the served folder
+	base: &'a Path,
+	// response headers picked up while routing, like `Allow` for a 405.
+	// Added to whatever response comes out, unless it sets them itself.
+	headers: Mutex<HeaderMap>,
+	// named groups matched by regex directories so far, given to executables
+	// as `PARAM_<name>` environment variables
+	captures: Mutex<Vec<(String, String)>>,
+	// when the processes started so far have to be done by, from their
+	// `timeout`s, and whether that was set for them instead of by default
+	deadline: Mutex<Option<(Instant, bool)>>,
+	// directories whose `.pre_process` already ran, which an alias could
+	// lead back through
+	pre_processed: Mutex<Vec<PathBuf>>,
+	// how many aliases have been followed, to stop loops
+	aliases: AtomicUsize,
+	// where the request came from
+	peer: Peer,
+	// the part of the path after the CGI script handling it
+	path_info: Mutex<Vec<String>>,
+	// the connection, for a proxied upgrade to take over
+	upgrade: Mutex<Option<OnUpgrade>>,
+}
+
+impl Context<'_> {
+	fn set_header(&self, name: HeaderName, value: HeaderValue) {
+		self.headers.lock().unwrap().insert(name, value);
+	}
+
+	// a 405, with the methods that would have worked
+	fn not_allowed(&self, allowed: &str) -> ProcessingState {
+		if let Ok(allowed) = HeaderValue::from_str(allowed) {
+			self.set_header(ALLOW, allowed);
+		}
+		ErrorCode(405)
+	}
+
+	// marks a directory as pre-processed, false if it already was
+	fn first_pre_process(&self, dir: &Path) -> bool {
+		let mut done = self.pre_processed.lock().unwrap();
+		if done.iter().any(|d| d == dir) {
+			return false;
+		}
+		done.push(dir.to_path_buf());
+		true
+	}
+
+	fn path_params<'p>(&self, params: &'p [String]) -> &'p [String] {
+		params.get(section_end(params, 1) + 1..).unwrap_or_default()
+	}
+
+	// the server-wide timeout only counts when nothing in the chain has one
+	// of its own
+	fn limit(&self, timeout: Option<Duration>) {
+		let (timeout, set) = match (timeout, self.config.timeout) {
+			(Some(timeout), _) => (timeout, true),
+			(None, Some(timeout)) => (timeout, false),
+			(None, None) => return,
+		};
+		let at = Instant::now() + timeout;
+		let mut deadline = self.deadline.lock().unwrap();
+		*deadline = match *deadline {
+			Some((d, true)) if !set => Some((d, true)),
+			Some((d, was_set)) if was_set == set => Some((d.min(at), set)),
+			_ => Some((at, set)),
+		};
+	}
+
+	// the deadline for what was started so far, which starts over after
+	fn take_deadline(&self) -> Option<Instant> {
+		self.deadline.lock().unwrap().take().map(|(d, _)| d)
+	}
+
+	fn env(&self) -> Vec<(String, String)> {
+		self.captures
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(name, value)| (format!("PARAM_{}", name), value.clone()))
+			.collect()
+	}
+}
+
 #[derive(Debug)]
 struct OriginWrap<T> {
 	data: T,
 	origin: PathBuf,
 }
 
+#[derive(Debug)]
+struct StaticFile {
+	file: File,
+	// set when a precompressed sibling is served in place of the origin
+	encoding: Option<Encoding>,
+	// whether the origin has precompressed siblings at all
+	has_variants: bool,
+	// `Content-Type`, when it's been decided already
+	mime: Option<String>,
+	// whether it gets an ETag and Last-Modified, which a file made for this
+	// request can't have
+	validators: bool,
+}
+
+impl From<File> for StaticFile {
+	fn from(file: File) -> Self {
+		StaticFile {
+			file,
+			encoding: None,
+			has_variants: false,
+			mime: None,
+			validators: true,
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Process {
+	// leads a process group of its own, with whatever it starts
+	child: Child,
+	// speaks CGI instead of the usual protocol, see `cgi`
+	cgi: bool,
+}
+
+impl Process {
+	// the whole group, so nothing it started in the background lives on
+	fn kill(&mut self) {
+		if let Some(pid) = self.child.id() {
+			// SAFETY: only sends a signal, to a group that's still ours as
+			// long as the child hasn't been waited for
+			unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+		}
+		let _ = self.child.start_kill();
+	}
+}
+
+impl Drop for Process {
+	fn drop(&mut self) {
+		if self.child.try_wait().is_ok_and(|s| s.is_none()) {
+			self.kill();
+		}
+	}
+}
+
+// a response from somewhere else, whose body hasn't been read yet
+struct RemoteResponse {
+	headers: Vec<(String, String)>,
+	body: ResponseBody,
+}
+
+impl std::fmt::Debug for RemoteResponse {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RemoteResponse")
+			.field("headers", &self.headers)
+			.finish_non_exhaustive()
+	}
+}
+
 #[derive(Debug)]
 struct HasStatus<T> {
 	data: T,
@@ -84,8 +303,14 @@ fn inner(s: BackTrackState) -> ProcessingState {
 enum ProcessingState {
 	ErrorCode(u16),
 	InternalError(u16, String),
-	Static(HasStatus<OriginWrap<File>>),
-	Chain(HasStatus<Vec<OriginWrap<Child>>>),
+	Static(HasStatus<OriginWrap<StaticFile>>),
+	Chain(HasStatus<Vec<OriginWrap<Process>>>),
+	// request body that has not been read yet.  The first executable gets it
+	// streamed into its stdin, anything else just drops it.
+	RequestBody(HasStatus<OriginWrap<Incoming>>),
+	// a response from a FastCGI app, an upstream server or a worker, which
+	// hasn't been read yet
+	Remote(HasStatus<OriginWrap<RemoteResponse>>),
 	HttpError(Error)
 }
 
@@ -100,7 +325,7 @@ impl ProcessingState {
 	fn halt_processing(&mut self) {
 		let Chain(proc) = self else { return };
 		for child in &mut proc.data {
-			let _ = child.data.kill();
+			child.data.kill();
 		}
 	}
 
@@ -110,6 +335,8 @@ impl ProcessingState {
 			InternalError(e, _) => *e,
 			Static(HasStatus { data: _, status: e }) => *e,
 			Chain(HasStatus { data: _, status: e }) => *e,
+			RequestBody(HasStatus { data: _, status: e }) => *e,
+			Remote(HasStatus { data: _, status: e }) => *e,
 			HttpError(_) => 500,
 		}
 	}
@@ -125,6 +352,14 @@ impl ProcessingState {
 				data:_,
 				status
 			}) => Ok(status),
+			RequestBody(HasStatus{
+				data:_,
+				status
+			}) => Ok(status),
+			Remote(HasStatus{
+				data:_,
+				status
+			}) => Ok(status),
 			// this method is used to decide what to do with a static file.
 			// need to decide how to handle the chain.  Want to at least
 			// completely resolve it.
@@ -140,6 +375,10 @@ impl ProcessingState {
 		match self {
 			ErrorCode(e) => Some(*e),
 			InternalError(e, _) => Some(*e),
+			// so an error from elsewhere still goes to `.error` handlers, but
+			// keeps its own body if there are none.  Switching protocols isn't
+			// one.
+			Remote(HasStatus { data: _, status }) if *status >= 300 => Some(*status),
 			_ => None
 		}
 	}
@@ -177,13 +416,58 @@ fn to_exit_code(res: Option<i32>) -> u16 {
 		.unwrap_or(500u16)
 }
 
+async fn is_executable(path: &Path) -> bool {
+	fs::metadata(path)
+		.await
+		.is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+// method handlers are named for their method, like `.GET` or `.DELETE`
+fn is_method_handler(name: &str) -> bool {
+	name.strip_prefix(".")
+		.is_some_and(|m| !m.is_empty() && m.bytes().all(|b| b.is_ascii_uppercase()))
+}
+
+/// Picks the file that handles a request for a directory: `.<METHOD>` if
+/// there is one (HEAD can also use `.GET`), and otherwise `.index`, which
+/// takes any method.  If there are only handlers for other methods, returns
+/// those methods for an `Allow` header instead, and `None` if there are no
+/// handlers at all.
+async fn method_handler(dir: &Path, method: &Method) -> Result<Option<String>, String> {
+	// hooks and other special files are lowercase, so a method can't name
+	// one unless it's the wrong case for a handler anyway
+	let own = format!(".{}", method);
+	let mut candidates = if is_method_handler(&own) { vec![own] } else { Vec::new() };
+	if method == Method::HEAD {
+		candidates.push(".GET".to_string());
+	}
+	candidates.push(".index".to_string());
+	let Some(dir) = routes::lookup(dir).await else {
+		return Ok(None);
+	};
+	if let Some(candidate) = candidates.into_iter().find(|c| dir.has(c)) {
+		return Ok(Some(candidate));
+	}
+	let allowed = dir
+		.entries()
+		.filter(|e| is_method_handler(e))
+		.map(|e| e[1..].to_string())
+		.collect::<Vec<String>>();
+	if allowed.is_empty() {
+		return Ok(None);
+	}
+	Err(settings::allow(allowed))
+}
+
 // args passed to commands are:
 // uri_path, METHOD "" headers "" url parameters "" path parameters (server does not get fragment)
-fn handle_file(
+async fn handle_file(
 	file: &Path,
 	mut prev_state: ProcessingState,
-	params: &Vec<String>,
-	pass_if_missing: bool
+	params: &[String],
+	pass_if_missing: bool,
+	settings: &Settings,
+	ctx: &Context<'_>
 ) -> ProcessingState {
 	// there are many time-of-check time-of-use race conditions here.
 	// this is fine, because it's not expecting to be serving from
@@ -192,11 +476,28 @@ fn handle_file(
 		return HttpError(e); // just forward it.  Don't know and isn't my responsibility to handle these
 	}
 	let mut file = file.to_path_buf();
-	if file.is_dir() {
-		file.push(".index")
+	if routes::lookup(&file).await.is_some() {
+		match method_handler(&file, &ctx.req.method).await {
+			Ok(Some(handler)) => file.push(handler),
+			Ok(None) if settings.listing() && [Method::GET, Method::HEAD].contains(&ctx.req.method) => {
+				prev_state.halt_processing();
+				return listing(&file, prev_state.status(), ctx).await;
+			}
+			// nothing here at all, so it's a 404
+			Ok(None) => file.push(".index"),
+			Err(allowed) => {
+				prev_state.halt_processing();
+				return ctx.not_allowed(&allowed);
+			}
+		}
 	}
-	if !file.exists() {
-		if prev_state.is_ok() && !pass_if_missing {
+	// the route tree knows when there's nothing there
+	let metadata = match routes::known(&file) {
+		Some(routes::Entry::Missing) => None,
+		_ => fs::metadata(&file).await.ok(),
+	};
+	let Some(metadata) = metadata else {
+		return if prev_state.is_ok() && !pass_if_missing {
 			prev_state.halt_processing();
 			ErrorCode(404)
 		} else {
@@ -204,12 +505,17 @@ fn handle_file(
 			// so, no need to halt processing.
 			// also if told to pass through (ex: for post-processing)
 			prev_state
-		}
-	} else if file.is_dir() {
+		};
+	};
+	if metadata.is_dir() {
 		// I am a teapot: I am a dir
 		prev_state.halt_processing();
 		ErrorCode(418)
-	} else if file.is_executable() {
+	} else if metadata.permissions().mode() & 0o111 != 0 {
+		// workers are for handlers, the same as CGI below
+		if let Some(workers) = settings.workers().filter(|_| !pass_if_missing) {
+			return worker(&file, prev_state, params, workers, metadata.modified().ok(), settings, ctx).await;
+		}
 		let Some(work_dir) = file.parent() else {
 			// if it cannot determine the parent, that means it's already at root.  Which is bad.
 			// and not just because this shouldn't be running on a dir
@@ -222,56 +528,119 @@ fn handle_file(
 				),
 			);
 		};
-		let Ok(headers) = tempfile() else {
-			prev_state.halt_processing();
-			return InternalError(500, String::from("Could not create header tempfile"));
-		};
+		let mut body = None;
+		// of a body that's a file by now, which can differ from the request's
+		let mut len = None;
+		// a script's output is passed on without its header block
+		let mut relay = None;
 		let (input_opt, mut prev_chain, status) = match prev_state {
-			Chain(mut v) => (
-				v.data
-					.last_mut()
-					.map(|c| c.data.stdout.take())
-					.unwrap_or(None)
-					.map(Stdio::from),
-				v.data,
-				v.status,
-			),
-			Static(b) => (Some(Stdio::from(b.data.data)), Vec::new(), b.status),
-			a => (tempfile().ok().map(Stdio::from), Vec::new(), a.status()),
+			Chain(mut v) => {
+				let last = v.data.last_mut();
+				let from_cgi = last.as_ref().is_some_and(|c| c.data.cgi);
+				let stdout = last.and_then(|c| c.data.child.stdout.take());
+				let input = if from_cgi {
+					relay = stdout;
+					relay.is_some().then(Stdio::piped)
+				} else {
+					stdout.and_then(|o| o.try_into().ok())
+				};
+				(input, v.data, v.status)
+			},
+			Static(b) => {
+				// processes always get the original, not a precompressed copy
+				let file = match b.data.data.encoding {
+					Some(_) => File::open(&b.data.origin).await.ok(),
+					None => Some(b.data.data.file),
+				};
+				let input = match file {
+					Some(f) => {
+						len = f.metadata().await.ok().map(|m| m.len());
+						Some(Stdio::from(f.into_std().await))
+					}
+					None => None,
+				};
+				(input, Vec::new(), b.status)
+			},
+			RequestBody(b) => {
+				body = Some(b.data.data.map_err(io::Error::other).boxed_unsync());
+				(Some(Stdio::piped()), Vec::new(), b.status)
+			},
+			Remote(b) => {
+				body = Some(b.data.data.body);
+				(Some(Stdio::piped()), Vec::new(), b.status)
+			},
+			a => (Some(Stdio::null()), Vec::new(), a.status()),
 		};
 		let Some(input) = input_opt else {
 			for mut c in prev_chain {
-				let _ = c.data.kill();
+				c.data.kill();
 			}
 			return InternalError(
 				500,
 				"Could not ascertain input from previous processing state".to_string(),
 			);
 		};
-		let Ok(child) = Command::new(&file)
+		// hooks and error handlers are the ones allowed to be missing, and
+		// those never speak CGI
+		let cgi = settings.cgi() && !pass_if_missing;
+		let mut command = Command::new(&file);
+		if cgi {
+			let path_info = ctx.path_info.lock().unwrap().clone();
+			let mut env = cgi::env(ctx.req, &ctx.peer, &path_info);
+			if let Some(len) = len {
+				cgi::set_content_length(&mut env, len);
+			}
+			command.envs(env);
+		} else {
+			command.args(params);
+		}
+		let Ok(mut child) = command
 			.current_dir(work_dir)
-			.args(params)
+			.envs(ctx.env())
 			.stdin(input)
-			.stderr(headers)
+			.stderr(Stdio::piped())
 			.stdout(Stdio::piped())
+			// nothing is left running if the client goes away
+			.kill_on_drop(true)
+			.process_group(0)
 			.spawn()
 		else {
 			for mut c in prev_chain {
-				let _ = c.data.kill();
+				c.data.kill();
 			}
 			return InternalError(
 				500,
 				format!("Error running command {}", file.to_string_lossy()),
 			);
 		};
+		ctx.limit(settings.timeout(&file));
+		if let Some(body) = body {
+			pump_body(body, child.stdin.take());
+		}
+		if let (Some(stdout), Some(prev)) = (relay, prev_chain.last()) {
+			cgi::relay_body(stdout, child.stdin.take(), prev.origin.clone());
+		}
+		// only the last process of a chain gets to set headers, so don't let
+		// the ones before it block on a full stderr pipe.
+		if let Some(prev) = prev_chain.last_mut() {
+			discard_headers(prev.data.child.stderr.take());
+		}
+		// scripts have their headers in stdout, stderr is only for the log
+		if cgi {
+			cgi::log_stderr(child.stderr.take(), file.clone(), "CGI");
+		}
 		prev_chain.push(OriginWrap {
-			data: child,
+			data: Process { child, cgi },
 			origin: file,
 		});
 		Chain(HasStatus {
 			data: prev_chain,
 			status,
 		})
+	} else if !pass_if_missing && ![Method::GET, Method::HEAD].contains(&ctx.req.method) {
+		// there's nothing to do with a static file but read it
+		prev_state.halt_processing();
+		ctx.not_allowed(&settings::allow(["GET".to_string()]))
 	} else {
 		// if exists, not executable, not a folder, return whatever original status,
 		// Content-type mime-type, and the file
@@ -281,15 +650,23 @@ fn handle_file(
 			Ok(c) => c,
 			Err(e) => {return e;}
 		};
-		let Ok(open_file) = File::open(&file) else {
+		let (served, encoding, has_variants) = precompressed(&file, ctx).await;
+		let mime = mime::mime_type(&file, settings.mime(), ctx.config.sniff_mime).await;
+		let Ok(open_file) = File::open(&served).await else {
 			return InternalError(
 				500,
-				format!("Couldn't open file {}", file.to_string_lossy()),
+				format!("Couldn't open file {}", served.to_string_lossy()),
 			);
 		};
 		Static(HasStatus {
 			data: OriginWrap {
-				data: open_file,
+				data: StaticFile {
+					file: open_file,
+					encoding,
+					has_variants,
+					mime: Some(mime),
+					validators: true,
+				},
 				origin: file,
 			},
 			status: c,
@@ -297,76 +674,626 @@ fn handle_file(
 	}
 }
 
+/// Picks a precompressed sibling of a static file (`app.js.br` for `app.js`)
+/// if the client accepts its encoding.  A sibling older than the file is left
+/// over from before it changed, and doesn't count.  Returns the file to
+/// serve, its encoding, and whether there were any siblings to pick from.
+async fn precompressed(file: &Path, ctx: &Context<'_>) -> (PathBuf, Option<Encoding>, bool) {
+	let sibling = |encoding: &Encoding| {
+		let mut sibling = file.as_os_str().to_owned();
+		sibling.push(".");
+		sibling.push(encoding.extension());
+		PathBuf::from(sibling)
+	};
+	// the route tree already knows what's next to the file
+	let dir = match file.parent() {
+		Some(parent) => routes::lookup(parent).await,
+		None => None,
+	};
+	let listed: Vec<Encoding> = match (dir, file.file_name().and_then(|n| n.to_str())) {
+		(Some(dir), Some(name)) => Encoding::ALL
+			.iter()
+			.filter(|encoding| dir.has(&format!("{}.{}", name, encoding.extension())))
+			.copied()
+			.collect(),
+		_ => Vec::new(),
+	};
+	let mut available = Vec::new();
+	if !listed.is_empty() {
+		let modified = |path: PathBuf| async move { fs::metadata(path).await.and_then(|m| m.modified()).ok() };
+		let original = modified(file.to_path_buf()).await;
+		for encoding in listed {
+			if modified(sibling(&encoding)).await >= original {
+				available.push(encoding);
+			}
+		}
+	}
+	match compress::negotiate(&ctx.req.headers, &available) {
+		Some(encoding) => (sibling(&encoding), Some(encoding), true),
+		None => (file.to_path_buf(), None, !available.is_empty()),
+	}
+}
+
+/// Has a persistent worker of a handler answer the request, see `workers`.
+async fn worker(
+	file: &Path,
+	prev_state: ProcessingState,
+	params: &[String],
+	workers: &Workers,
+	modified: Option<SystemTime>,
+	settings: &Settings,
+	ctx: &Context<'_>
+) -> ProcessingState {
+	let (body, _) = request_body(prev_state, ctx).await;
+	let body = match body.collect().await {
+		Ok(body) => body.to_bytes(),
+		Err(e) => return InternalError(500, format!("Could not read body for {}: {}", file.display(), e)),
+	};
+	ctx.limit(settings.timeout(file));
+	let deadline = ctx.take_deadline();
+	let env = ctx.env();
+	let answer = workers::handle(file, workers, modified, params, &env, &body);
+	match within(deadline, answer).await {
+		Some(Ok(answer)) => Remote(HasStatus {
+			data: OriginWrap {
+				data: RemoteResponse {
+					headers: answer.headers,
+					body: full(answer.body),
+				},
+				origin: file.to_path_buf(),
+			},
+			status: answer.status,
+		}),
+		Some(Err((status, e))) => InternalError(status, e),
+		None => {
+			log!(error "TIMEOUT"; "{} timed out", file.display());
+			ErrorCode(504)
+		}
+	}
+}
+
+/// A generated page listing a directory that has no handler of its own.
+async fn listing(dir: &Path, status: u16, ctx: &Context<'_>) -> ProcessingState {
+	let page = async {
+		let page = listing::render(dir, ctx.req.uri.path()).await?;
+		let mut file = File::from_std(spawn_blocking(tempfile).await.map_err(io::Error::other)??);
+		file.write_all(page.as_bytes()).await?;
+		file.flush().await?;
+		file.rewind().await?;
+		io::Result::Ok(file)
+	}.await;
+	match page {
+		Ok(file) => Static(HasStatus {
+			data: OriginWrap {
+				data: StaticFile {
+					mime: Some("text/html; charset=utf-8".to_string()),
+					validators: false,
+					..file.into()
+				},
+				origin: dir.to_path_buf(),
+			},
+			status,
+		}),
+		Err(e) => InternalError(
+			500,
+			format!("Could not list directory {}: {}", dir.display(), e)
+		),
+	}
+}
+
+/// Feeds a body into a process as it arrives.  Only reads the next
+/// frame once the last one has been written, so a slow reader holds back the
+/// client instead of filling memory.
+fn pump_body(mut body: ResponseBody, stdin: Option<ChildStdin>) {
+	let Some(mut stdin) = stdin else {
+		return;
+	};
+	tokio::spawn(async move {
+		while let Some(frame) = body.frame().await {
+			let frame = match frame {
+				Ok(frame) => frame,
+				Err(e) => {
+					log!(error "ERROR"; "Error reading body to pipe into a process: {}", e);
+					return;
+				}
+			};
+			let Ok(data) = frame.into_data() else {
+				continue;
+			};
+			// the process is allowed to stop reading early
+			if stdin.write_all(&data).await.is_err() {
+				return;
+			}
+		}
+		// stdin is dropped here, closing it
+	});
+}
+
+fn discard_headers(stderr: Option<ChildStderr>) {
+	let Some(mut stderr) = stderr else {
+		return;
+	};
+	tokio::spawn(async move {
+		let _ = tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await;
+	});
+}
+
+// aliases can point at each other, this many is surely a loop
+const MAX_ALIASES: usize = 8;
+
+// error handlers can fail in turn, and be sent back to themselves
+const MAX_REROUTES: usize = 8;
+
 const SPECIAL_FOLDERS :  &[&str] = &[
 	".error"
 ];
 
-fn handle_layer(
+// params are split into sections by empty strings, after the uri path and
+// method: headers, url parameters, then path parameters
+fn section_end(params: &[String], section: usize) -> usize {
+	params
+		.iter()
+		.enumerate()
+		.filter(|(_, p)| p.is_empty())
+		.nth(section + 1)
+		.map_or(params.len(), |(i, _)| i)
+}
+
+// the `content-length` header among the params, for a body that changed
+fn set_length_param(params: &mut Vec<String>, len: u64) {
+	let end = section_end(params, 0);
+	let start = params.iter().position(String::is_empty).map_or(end, |i| i + 1);
+	let param = format!("content-length={}", len);
+	match (start..end).find(|i| params[*i].starts_with("content-length=")) {
+		Some(i) => params[i] = param,
+		None => params.insert(end, param),
+	}
+}
+
+fn add_params(params: &mut Vec<String>, added: &str) {
+	for line in added.lines().filter(|l| !l.is_empty()) {
+		if let Some(param) = line.strip_prefix("?") {
+			if !param.is_empty() {
+				params.insert(section_end(params, 1), param.to_string());
+			}
+		} else if let Some(param) = line.strip_prefix("/") {
+			params.push(param.to_string());
+		} else if line.contains("=") {
+			params.insert(section_end(params, 0), line.to_string());
+		}
+	}
+}
+
+/// Runs the `.pre_process` of a directory on the way down, before anything
+/// deeper gets the request.  Its stdout replaces the request body, and the
+/// `content-length` everything after it is told about, and its stderr adds
+/// to the parameters of everything after it, one per line: `k=v` for a
+/// header, `?k=v` for a url parameter, and `/p` for a path parameter.
+/// Exiting with an error stops the request there.
+async fn pre_process(
+	curr_layer: &mut PathBuf,
+	body: ProcessingState,
+	params: &mut Vec<String>,
+	settings: &Settings,
+	ctx: &Context<'_>
+) -> ProcessingState {
+	curr_layer.push(".pre_process");
+	let state = handle_file(curr_layer, body, params, true, settings, ctx).await;
+	curr_layer.pop();
+	let Chain(HasStatus { data: mut chain, status }) = state else {
+		return state;
+	};
+	let Some(last) = chain.last_mut() else {
+		return InternalError(500, "Pre-processing produced an empty chain".to_string());
+	};
+	let origin = last.origin.clone();
+	let (Some(mut stdout), Some(mut stderr)) = (last.data.child.stdout.take(), last.data.child.stderr.take()) else {
+		last.data.kill();
+		return InternalError(500, format!("Could not capture output of {}", origin.display()));
+	};
+	let mut added = Vec::new();
+	let deadline = ctx.take_deadline();
+	let out = within(deadline, async {
+		let mut out = File::from_std(spawn_blocking(tempfile).await.map_err(io::Error::other)??);
+		tokio::try_join!(
+			tokio::io::copy(&mut stdout, &mut out),
+			stderr.read_to_end(&mut added)
+		)?;
+		out.flush().await?;
+		out.rewind().await?;
+		io::Result::Ok(out)
+	}).await;
+	let out = match out {
+		Some(Ok(out)) => out,
+		Some(Err(e)) => {
+			last.data.kill();
+			return InternalError(
+				500,
+				format!("Could not capture output of {}: {}", origin.display(), e)
+			);
+		}
+		None => {
+			last.data.kill();
+			log!(error "TIMEOUT"; "{} timed out", origin.display());
+			return ErrorCode(504);
+		}
+	};
+	match within(deadline, wait_chain(&mut chain)).await {
+		Some(Ok(None)) => {}
+		Some(Ok(Some((_, code)))) => return ErrorCode(code),
+		Some(Err(e)) => return e,
+		None => {
+			for child in &mut chain {
+				child.data.kill();
+			}
+			log!(error "TIMEOUT"; "{} timed out", origin.display());
+			return ErrorCode(504);
+		}
+	}
+	if let Ok(metadata) = out.metadata().await {
+		set_length_param(params, metadata.len());
+	}
+	add_params(params, &String::from_utf8_lossy(&added));
+	Static(HasStatus {
+		data: OriginWrap {
+			data: out.into(),
+			origin,
+		},
+		status,
+	})
+}
+
+/// Answers with a redirect to where a `.redirect` points.
+async fn redirect(
+	curr_layer: &Path,
+	remaining_layers: &[String],
+	params: &[String],
+	ctx: &Context<'_>
+) -> ProcessingState {
+	let file = curr_layer.join(rewrite::REDIRECT_FILE);
+	let parsed = fs::read_to_string(&file)
+		.await
+		.map_err(|e| e.to_string())
+		.and_then(|content| rewrite::parse_redirect(&content));
+	let (target, status) = match parsed {
+		Ok(parsed) => parsed,
+		Err(e) => return InternalError(500, format!("Bad redirect {}: {}", file.display(), e)),
+	};
+	// captures are decoded, and have to go back into a url
+	let captures = ctx.captures
+		.lock()
+		.unwrap()
+		.iter()
+		.map(|(name, value)| (name.clone(), decode::percent_encode(value)))
+		.collect::<Vec<_>>();
+	let path_params = ctx.path_params(params)
+		.iter()
+		.map(|p| decode::percent_encode(p))
+		.collect::<Vec<_>>();
+	let target = rewrite::substitute(&target, &path_params, &captures);
+	let Ok(location) = HeaderValue::from_str(&rewrite::redirect_location(&target, remaining_layers)) else {
+		return InternalError(500, format!("Bad redirect {}: invalid location {}", file.display(), target));
+	};
+	ctx.set_header(LOCATION, location);
+	ErrorCode(status)
+}
+
+/// The request body as it is by now, as something to send on, and its length
+/// if that's known.
+async fn request_body(state: ProcessingState, ctx: &Context<'_>) -> (ResponseBody, Option<u64>) {
+	match state {
+		Static(b) => {
+			let file = b.data.data.file;
+			let len = file.metadata().await.ok().map(|m| m.len());
+			(StreamBody::new(ReaderStream::new(file).map_ok(Frame::data)).boxed_unsync(), len)
+		}
+		RequestBody(b) => {
+			let len = ctx.req.headers
+				.get(CONTENT_LENGTH)
+				.and_then(|l| l.to_str().ok())
+				.and_then(|l| l.parse().ok());
+			(b.data.data.map_err(io::Error::other).boxed_unsync(), len)
+		}
+		Remote(b) => (b.data.data.body, None),
+		mut other => {
+			other.halt_processing();
+			(full(Bytes::new()), Some(0))
+		}
+	}
+}
+
+/// Hands the request to the FastCGI app a `.fastcgi` names.
+async fn fastcgi(
+	curr_layer: &Path,
+	remaining_layers: &[String],
+	incoming_body: ProcessingState,
+	settings: &Settings,
+	ctx: &Context<'_>
+) -> ProcessingState {
+	let file = curr_layer.join(fastcgi::FASTCGI_FILE);
+	// the app could serve the file, so hidden ones stay hidden
+	if remaining_layers.iter().any(|l| l.starts_with(".") || l.starts_with("&")) {
+		return ErrorCode(403);
+	}
+	let address = match fs::read_to_string(&file).await {
+		Ok(content) => match fastcgi::parse_address(&content) {
+			Some(address) => address.to_string(),
+			None => return InternalError(500, format!("Bad FastCGI file {}: no address", file.display())),
+		},
+		Err(e) => return InternalError(500, format!("Could not read {}: {}", file.display(), e)),
+	};
+	let (body, len) = request_body(incoming_body, ctx).await;
+	let mut env = cgi::env(ctx.req, &ctx.peer, remaining_layers);
+	if let Some(len) = len {
+		cgi::set_content_length(&mut env, len);
+	}
+	let script = remaining_layers.iter().fold(curr_layer.to_path_buf(), |p, l| p.join(l));
+	env.push(("SCRIPT_FILENAME".to_string(), script.display().to_string()));
+	env.push(("DOCUMENT_ROOT".to_string(), ctx.base.display().to_string()));
+	env.extend(ctx.env());
+	ctx.limit(settings.timeout(&file));
+	let deadline = ctx.take_deadline();
+	match within(deadline, fastcgi::request(&address, env, body, file.clone())).await {
+		Some(Ok(((headers, status), body))) => Remote(HasStatus {
+			data: OriginWrap {
+				data: RemoteResponse { headers, body },
+				origin: file,
+			},
+			status: status.unwrap_or(200),
+		}),
+		Some(Err(e)) => InternalError(502, format!("FastCGI app for {} failed: {}", file.display(), e)),
+		None => {
+			log!(error "TIMEOUT"; "{} timed out", file.display());
+			ErrorCode(504)
+		}
+	}
+}
+
+/// Forwards the request to the upstream a `.proxy` names.
+async fn proxy(
+	curr_layer: &Path,
+	remaining_layers: &[String],
+	incoming_body: ProcessingState,
+	settings: &Settings,
+	ctx: &Context<'_>
+) -> ProcessingState {
+	let file = curr_layer.join(proxy::PROXY_FILE);
+	let upstream = match fs::read_to_string(&file).await {
+		Ok(content) => match proxy::parse_upstream(&content) {
+			Some(upstream) => upstream.to_string(),
+			None => return InternalError(500, format!("Bad proxy file {}: no upstream", file.display())),
+		},
+		Err(e) => return InternalError(500, format!("Could not read {}: {}", file.display(), e)),
+	};
+	let uri = proxy::upstream_uri(&upstream, remaining_layers, ctx.req);
+	let (body, len) = request_body(incoming_body, ctx).await;
+	// a stream of unknown length would be sent chunked, even with nothing in it
+	let body = if len == Some(0) { full(Bytes::new()) } else { body };
+	let upgrade = ctx.upgrade.lock().unwrap().take();
+	ctx.limit(settings.timeout(&file));
+	let deadline = ctx.take_deadline();
+	match within(deadline, proxy::forward(&uri, ctx.req, &ctx.peer, body, len, upgrade)).await {
+		Some(Ok(response)) => {
+			let status = response.status().as_u16();
+			let (parts, body) = response.into_parts();
+			let headers = parts.headers
+				.iter()
+				.filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+				.collect();
+			Remote(HasStatus {
+				data: OriginWrap {
+					data: RemoteResponse {
+						headers,
+						body: body.map_err(io::Error::other).boxed_unsync(),
+					},
+					origin: file,
+				},
+				status,
+			})
+		}
+		Some(Err(e)) => InternalError(502, format!("Proxy {} failed: {}", file.display(), e)),
+		None => {
+			log!(error "TIMEOUT"; "{} timed out", file.display());
+			ErrorCode(504)
+		}
+	}
+}
+
+/// Routes a request again from the top, to where an `.alias` points.
+async fn alias(
+	curr_layer: &Path,
+	remaining_layers: &[String],
+	params: &mut Vec<String>,
+	mut incoming_body: ProcessingState,
+	ctx: &Context<'_>
+) -> ProcessingState {
+	let file = curr_layer.join(rewrite::ALIAS_FILE);
+	if ctx.aliases.fetch_add(1, Ordering::Relaxed) >= MAX_ALIASES {
+		incoming_body.halt_processing();
+		log!(error "ERROR"; "Too many aliases, last one at {}", file.display());
+		return ErrorCode(508);
+	}
+	let content = match fs::read_to_string(&file).await {
+		Ok(content) => content,
+		Err(e) => {
+			incoming_body.halt_processing();
+			return InternalError(500, format!("Could not read alias {}: {}", file.display(), e));
+		}
+	};
+	let captures = ctx.captures.lock().unwrap().clone();
+	let target = rewrite::substitute(
+		rewrite::parse_alias(&content).unwrap_or(""),
+		ctx.path_params(params),
+		&captures
+	);
+	let layers = target
+		.split("/")
+		.filter(|p| !p.is_empty())
+		.map(String::from)
+		.chain(remaining_layers.iter().cloned())
+		.collect::<Vec<String>>();
+	inner(Box::pin(handle_layer(
+		&mut ctx.base.to_path_buf(),
+		&layers,
+		params,
+		incoming_body,
+		&Settings::default(),
+		ctx
+	)).await)
+}
+
+/// Where the next segment of a path leads from a directory: an entry by that
+/// name, or else the first regex directory to match it.
+struct NextLayer {
+	name: String,
+	// every group of the regex, by position
+	groups: Vec<String>,
+	// the named ones, by name
+	named: Vec<(String, String)>,
+}
+
+fn next_layer(dir: Option<&routes::Dir>, remaining_layers: &[String]) -> Option<NextLayer> {
+	let (dir, part) = dir.zip(remaining_layers.first())?;
+	if part.starts_with(".") || part.starts_with("&") {
+		return None;
+	}
+	if dir.has(part) {
+		return Some(NextLayer {
+			name: part.clone(),
+			groups: Vec::new(),
+			named: Vec::new(),
+		});
+	}
+	dir.regexes.iter().find_map(|(name, re)| {
+		let capture = re.captures(part)?;
+		Some(NextLayer {
+			name: name.clone(),
+			groups: capture
+				.iter()
+				.map(|m| m.map(|m| m.as_str()).unwrap_or("").to_string())
+				.collect(),
+			named: re
+				.capture_names()
+				.flatten()
+				.filter_map(|name| Some((name.to_string(), capture.name(name)?.as_str().to_string())))
+				.collect(),
+		})
+	})
+}
+
+/// Routes the rest of the path from where the next segment leads.
+async fn descend(
+	curr_layer: &mut PathBuf,
+	next: NextLayer,
+	remaining_layers: &[String],
+	params: &mut Vec<String>,
+	incoming_body: ProcessingState,
+	settings: &Settings,
+	ctx: &Context<'_>
+) -> BackTrackState {
+	params.extend(next.groups);
+	// named groups are also passed by name.  A deeper directory can shadow a
+	// name, it comes later in the environment.
+	ctx.captures.lock().unwrap().extend(next.named);
+	curr_layer.push(next.name);
+	let r = Box::pin(
+		handle_layer(curr_layer, &remaining_layers[1..], params, incoming_body, settings, ctx)
+	).await?;
+	curr_layer.pop();
+	BackTrack(r)
+}
+
+async fn handle_layer(
 	curr_layer: &mut PathBuf,
 	remaining_layers: &[String],
 	params: &mut Vec<String>,
 	incoming_body: ProcessingState,
+	inherited: &Settings,
+	ctx: &Context<'_>
 ) -> BackTrackState {
-	let res = if remaining_layers.is_empty() {
-		handle_file(curr_layer, incoming_body, params, false)
+	let dir = routes::lookup(curr_layer).await;
+	let has = |name: &str| dir.as_ref().is_some_and(|d| d.has(name));
+	let settings = match &dir {
+		Some(dir) => inherited.merge(&dir.settings),
+		None => inherited.clone(),
+	};
+	// deeper directories go later, so theirs win
+	for (name, value) in settings.headers() {
+		ctx.set_header(name, value);
+	}
+	// an error sent back from a handler that failed only comes down again to
+	// get to the `.error` handlers between there and the top, nothing else
+	// runs for it
+	let rerouted = incoming_body.error_code().is_some();
+	// an alias is served from where it points, which does its own
+	// post-processing on the way back
+	if !rerouted && has(rewrite::ALIAS_FILE) {
+		return Done(alias(curr_layer, remaining_layers, params, incoming_body, ctx).await);
+	}
+	let redirects = has(rewrite::REDIRECT_FILE);
+	let mut incoming_body = if !rerouted
+		&& !redirects
+		&& has(".pre_process")
+		&& ctx.first_pre_process(curr_layer)
+	{
+		pre_process(curr_layer, incoming_body, params, &settings, ctx).await
+	} else {
+		incoming_body
+	};
+	// misses while routing can fall back, errors coming in from elsewhere can't
+	let routed = incoming_body.error_code().is_none();
+	let res = if rerouted {
+		match next_layer(dir.as_deref(), remaining_layers) {
+			Some(next) => descend(curr_layer, next, remaining_layers, params, incoming_body, &settings, ctx).await?,
+			// back where it came from
+			None => incoming_body,
+		}
+	} else if !routed {
+		// turned away by pre-processing
+		incoming_body
+	} else if redirects {
+		incoming_body.halt_processing();
+		redirect(curr_layer, remaining_layers, params, ctx).await
+	} else if let Some(allowed) = settings
+		.disallowed(&ctx.req.method)
+		// a `.fastcgi` or `.proxy` answers for the rest of the path itself
+		.filter(|_| remaining_layers.is_empty() || has(fastcgi::FASTCGI_FILE) || has(proxy::PROXY_FILE))
+	{
+		incoming_body.halt_processing();
+		ctx.not_allowed(&allowed)
+	} else if has(fastcgi::FASTCGI_FILE) {
+		fastcgi(curr_layer, remaining_layers, incoming_body, &settings, ctx).await
+	} else if has(proxy::PROXY_FILE) {
+		proxy(curr_layer, remaining_layers, incoming_body, &settings, ctx).await
+	} else if remaining_layers.is_empty() {
+		handle_file(curr_layer, incoming_body, params, false, &settings, ctx).await
 	} else if remaining_layers[0].starts_with(".") || remaining_layers[0].starts_with("&") {
 		// hide hidden files/directories and prevent escape through '..'
 		// also hide regex paths
 		ErrorCode(403)
-	} else if let Ok(mut dir_content) = read_dir(curr_layer.clone())
-		.map(
-			|r| r
-				.filter_map(Result::ok)
-				.filter_map(|e| DirEntry::file_name(&e).to_str().map(String::from))
-				.collect::<Vec<String>>()
-		)
-	{
-		let part = remaining_layers[0].clone();
-		// prevent undefined behavior when multiple regexes match the part
-		dir_content.sort();
-		if dir_content.contains(&part) {
-			curr_layer.push(part);
-			let r = handle_layer(curr_layer, &remaining_layers[1..], params, incoming_body)?;
-			curr_layer.pop();
-			r
-		} else if let Some((p, capture)) = dir_content
-				.iter()
-				.filter_map(
-				|s|
-				Some(s).zip(
-					s
-						.strip_prefix("&")
-						.map(Regex::new)
-						.and_then(Result::ok)
-						.and_then(|r| r.captures(part.as_str()))
-				)
-			)
-			.next()
-		{
-			curr_layer.push(p);
-			params.extend(capture
-				.iter()
-				.map(|o| o
-					 .map(|m| m.as_str())
-					 .unwrap_or("")
-					 .to_string()
-				)
-			);
-			curr_layer.push(p);
-			let r = handle_layer(curr_layer, &remaining_layers[1..], params, incoming_body)?;
-			curr_layer.pop();
-			r
-		} else {
-			ErrorCode(404)
-		}
+	} else if let Some(next) = next_layer(dir.as_deref(), remaining_layers) {
+		descend(curr_layer, next, remaining_layers, params, incoming_body, &settings, ctx).await?
+	} else if dir.is_none() && settings.cgi() && is_executable(curr_layer).await {
+		// the rest of the path is for the script
+		*ctx.path_info.lock().unwrap() = remaining_layers.to_vec();
+		handle_file(curr_layer, incoming_body, params, false, &settings, ctx).await
 	} else {
 		ErrorCode(404)
 	};
 
-	let res = if let Some(error) = res.error_code() {
+	// single page apps answer any path that doesn't exist with their page
+	let res = match settings.fallback(&ctx.req.method, &ctx.req.headers) {
+		Some(fallback) if routed && matches!(res, ErrorCode(404)) => {
+			// nothing comes before it, so it gets an empty input
+			handle_file(fallback, ErrorCode(200), params, false, &settings, ctx).await
+		}
+		_ => res,
+	};
+
+	let res = if let Some(error) = res.error_code().filter(|_| has(".error")) {
 		curr_layer.push(".error");
 		curr_layer.push(error.to_string());
-		let r = handle_file(curr_layer, res, params, true);
+		let r = handle_file(curr_layer, res, params, true, &settings, ctx).await;
 		curr_layer.pop();
 		curr_layer.pop();
 		r
@@ -375,24 +1302,32 @@ fn handle_layer(
 	};
 	
 	// if there is a post-processing file and current body is OK, put it through the file
-	let res = if res.error_code().is_none() {
+	// a switched protocol is for the upstream and the client alone
+	let res = if res.error_code().is_none() && res.status() != 101 && has(".post_process") {
 		curr_layer.push(".post_process");
-		let r = handle_file(curr_layer, res, params, true);
+		let r = handle_file(curr_layer, res, params, true, &settings, ctx).await;
 		curr_layer.pop();
 		r
 	} else {
 		res
 	};
 	// if there is a base file, stop the backtracking and post-processing
-	curr_layer.push(".base");
-	if curr_layer.exists() {
+	if has(".base") {
+		curr_layer.push(".base");
 		return Done(res);
 	}
-	curr_layer.pop();
 	BackTrack(res)
 }
 
-fn error_response (e: u16) -> Result<Response<Full<Bytes>>, Error> {
+pub type ResponseBody = UnsyncBoxBody<Bytes, io::Error>;
+
+fn full(data: impl Into<Bytes>) -> ResponseBody {
+	Full::new(data.into())
+		.map_err(|e: Infallible| match e {})
+		.boxed_unsync()
+}
+
+fn error_response (e: u16) -> Result<Response<ResponseBody>, Error> {
 	let message = format!(
 		"Error {}: That's all we know",
 		e
@@ -401,15 +1336,413 @@ fn error_response (e: u16) -> Result<Response<Full<Bytes>>, Error> {
 		.status(e)
 		.header("Content-Type", "text/plain; charset=us-ascii")
 		.header("Content-Length", message.len())
-		.body(Full::new(Bytes::from(message)))
+		.body(full(message))
 }
 
-fn resolve_to_response_inner(
+/// The headers of an executable's response, if the client already has it.
+/// What's compared is the tag it would be sent with, since compressing it on
+/// the way out makes it another representation, with a tag of its own.
+fn revalidated(headers: &[(String, String)], len: Option<u64>, ctx: &Context<'_>) -> Option<Vec<(String, String)>> {
+	let header = |name: &str| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str());
+	let etag = header("etag")?;
+	let (varies, compressed) = match (header("content-type"), header("content-encoding")) {
+		(Some(kind), None) => {
+			let len = header("content-length").and_then(|l| l.parse().ok()).or(len);
+			compress::choose(kind, len, &ctx.req.headers, ctx.config)
+		}
+		_ => (false, None),
+	};
+	let sent = match compressed {
+		Some(compressed) => compress::variant_tag(etag, compressed),
+		None => etag.to_string(),
+	};
+	if !conditional::not_modified(&ctx.req.headers, Some(&sent), None) {
+		return None;
+	}
+	let mut kept = headers
+		.iter()
+		.filter(|(k, _)| !k.eq_ignore_ascii_case("etag"))
+		.cloned()
+		.collect::<Vec<_>>();
+	kept.push(("ETag".to_string(), sent));
+	let listed = headers
+		.iter()
+		.any(|(k, v)| k.eq_ignore_ascii_case("vary") && v.to_ascii_lowercase().contains("accept-encoding"));
+	if varies && !listed {
+		kept.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+	}
+	Some(kept)
+}
+
+/// A 304 in place of a full response, keeping only the headers it's allowed to.
+fn not_modified_response(headers: Vec<(String, String)>) -> Result<Response<ResponseBody>, Error> {
+	headers
+		.into_iter()
+		.filter(|(k, _)| conditional::NOT_MODIFIED_HEADERS.contains(&k.to_ascii_lowercase().as_str()))
+		.fold(Builder::new().status(304), |b, (k, v)| b.header(k, v))
+		.body(full(Bytes::new()))
+}
+
+/// waits on every process in a chain, in order.  If one of them fails, kills
+/// the rest and returns where it failed and the status it failed with.
+async fn wait_chain(chain: &mut [OriginWrap<Process>]) -> Result<Option<(PathBuf, u16)>, ProcessingState> {
+	let mut error: Option<(PathBuf, u16)> = None;
+	for OriginWrap {
+		data: child,
+		origin,
+	} in chain.iter_mut()
+	{
+		if error.is_none() {
+			let status_data = child.child.wait().await.map_err(|e| {
+				InternalError(
+					500,
+					format!("Error resolving process chain at {}: {}", origin.display(), e),
+				)
+			})?;
+			let code = match child.cgi {
+				true if status_data.success() => 200,
+				true => 500,
+				false => to_exit_code(status_data.code()),
+			};
+			if status_is_ok(code) {
+				continue;
+			}
+			error = Some((origin.clone(), code))
+		} else {
+			child.kill();
+		}
+	}
+	Ok(error)
+}
+
+// output held back while waiting for headers, past this it streams anyway
+const MAX_BUFFERED_OUTPUT: usize = 1024 * 1024;
+
+/// The header channel of the last process in a chain is its stderr, with one
+/// `k=v` header per line.  The block ends when stderr is closed (usually by
+/// exiting), or early on an empty line, which lets the response start
+/// streaming while the process is still running.  Stdout is buffered in the
+/// meantime so a chatty process can't deadlock against its own headers, up to
+/// `MAX_BUFFERED_OUTPUT`, after which the response streams with the headers
+/// it has so far.
+///
+/// Returns the headers, and whether the block was ended early.
+async fn read_header_block(
+	stderr: ChildStderr,
+	stdout: &mut ChildStdout,
+	buffered: &mut Vec<u8>,
+	origin: &Path,
+) -> Result<(Vec<(String, String)>, bool), ProcessingState> {
+	let mut stderr = BufReader::new(stderr);
+	let mut headers = Vec::new();
+	let mut line = Vec::new();
+	let mut stdout_open = true;
+	loop {
+		tokio::select! {
+			read = stderr.read_until(b'\n', &mut line) => {
+				let read = read.map_err(
+					|e| InternalError(500, format!("Error reading header output: {}", e))
+				)?;
+				if read == 0 {
+					return Ok((headers, false));
+				}
+				let header = String::from_utf8(std::mem::take(&mut line))
+					.map_err(
+						|e| InternalError(
+							500,
+							format!("Error reading utf-8 from header output: {}", e)
+						)
+					)?;
+				let header = header.trim_end_matches(['\n', '\r']);
+				if header.is_empty() {
+					return Ok((headers, true));
+				}
+				if let Some((k, v)) = header.split_once("=") {
+					headers.push((k.to_string(), v.to_string()));
+				}
+			}
+			read = stdout.read_buf(buffered), if stdout_open => {
+				stdout_open = read.map_err(
+					|e| InternalError(500, format!("End of chain could not capture output: {}", e))
+				)? != 0;
+				if buffered.len() >= MAX_BUFFERED_OUTPUT {
+					log!(error "STREAM"; "{} started streaming before its headers were done", origin.display());
+					// headers written from here on are ignored, but mustn't block it
+					discard_headers(Some(stderr.into_inner()));
+					return Ok((headers, true));
+				}
+			}
+		}
+	}
+}
+
+/// Body for a chain whose headers were sent before it finished.  Sends what
+/// was buffered, then stdout as it comes, and then errors out if any of the
+/// processes failed or the deadline passed, since it's too late to change the
+/// status.  The chain goes with the body, so it's killed if the client goes
+/// away in the middle.
+fn chain_body(
+	buffered: Vec<u8>,
+	stdout: ChildStdout,
+	mut chain: Vec<OriginWrap<Process>>,
+	deadline: Option<Instant>,
+) -> ResponseBody {
+	let origin = chain.last().map(|c| c.origin.clone()).unwrap_or_default();
+	let tail = stream::once(async move {
+		let failure = match wait_chain(&mut chain).await {
+			Ok(None) => return None,
+			Ok(Some((origin, code))) => format!(
+				"{} exited with status {} after its output started streaming",
+				origin.display(),
+				code
+			),
+			Err(e) => format!("{:?}", e),
+		};
+		log!(error "ERROR"; "{}", failure);
+		Some(Err(io::Error::other(failure)))
+	})
+		.filter_map(std::future::ready);
+	let frames = stream::iter([Ok(Bytes::from(buffered))])
+		.chain(ReaderStream::new(stdout))
+		.map_ok(Frame::data)
+		.chain(tail);
+	let Some(deadline) = deadline else {
+		return StreamBody::new(frames).boxed_unsync();
+	};
+	let expired = Box::pin(tokio::time::sleep_until(deadline));
+	let frames = stream::unfold(Some((Box::pin(frames), expired)), move |state| {
+		let origin = origin.clone();
+		async move {
+			let (mut frames, mut expired) = state?;
+			tokio::select! {
+				frame = frames.next() => frame.map(|f| (f, Some((frames, expired)))),
+				// dropping the frames drops the chain, which kills it
+				_ = &mut expired => {
+					log!(error "TIMEOUT"; "{} timed out after its output started streaming", origin.display());
+					Some((Err(io::Error::other("timed out")), None))
+				}
+			}
+		}
+	});
+	StreamBody::new(frames).boxed_unsync()
+}
+
+fn range_len(range: &RangeInclusive<u64>) -> u64 {
+	range.end() - range.start() + 1
+}
+
+/// Part of a file, read as it's sent.  Seeks when it starts, so the file can
+/// be shared with other parts that are sent before it.
+fn read_range(f: File, range: RangeInclusive<u64>) -> impl Stream<Item = io::Result<Bytes>> + Send {
+	stream::once(async move {
+		let mut f = f;
+		f.seek(SeekFrom::Start(*range.start())).await?;
+		io::Result::Ok(ReaderStream::new(f.take(range_len(&range))))
+	})
+		.try_flatten()
+}
+
+/// Static files are streamed whole, unless a successful GET asks for only
+/// part of them with `Range`.
+async fn resolve_static(
+	f: StaticFile,
+	p: PathBuf,
+	status: u16,
+	ctx: &Context<'_>
+) -> Result<Result<Response<ResponseBody>, Error>, ProcessingState> {
+	let Context { req, config, .. } = ctx;
+	let StaticFile { file: mut f, encoding, has_variants, mime, validators } = f;
+	let read_error = |e: io::Error| InternalError(500, format!("Couldn't read file {}: {}", p.display(), e));
+	let metadata = f.metadata().await.map_err(read_error)?;
+	let len = metadata.len();
+	let modified = metadata.modified().ok();
+	// error pages don't get validators, they aren't the resource
+	let cacheable = validators && status == 200 && [Method::GET, Method::HEAD].contains(&req.method);
+	let etag = if cacheable {
+		conditional::etag(&p, &mut f, &metadata, config.strong_etags).await
+	} else {
+		None
+	};
+	let mut builder = Builder::new()
+		.header("Accept-Ranges", "bytes");
+	if let Some(encoding) = encoding {
+		builder = builder.header("Content-Encoding", encoding.name());
+	}
+	if has_variants {
+		builder = builder.header("Vary", "Accept-Encoding");
+	}
+	let mimetype = match mime {
+		Some(mime) => mime,
+		None => mime::mime_type(&p, &Default::default(), config.sniff_mime).await,
+	};
+	if cacheable {
+		if let Some(modified) = modified {
+			builder = builder.header("Last-Modified", conditional::http_date(modified));
+		}
+		// compressing it on the way out makes it another representation,
+		// with a tag of its own
+		let (varies, compressed) = match encoding {
+			None => compress::choose(&mimetype, Some(len), &req.headers, config),
+			Some(_) => (false, None),
+		};
+		let sent = etag.as_deref().map(|etag| match compressed {
+			Some(compressed) => compress::variant_tag(etag, compressed),
+			None => etag.to_string(),
+		});
+		if conditional::not_modified(&req.headers, sent.as_deref(), modified) {
+			if let Some(sent) = sent {
+				builder = builder.header("ETag", sent);
+			}
+			if varies && !has_variants {
+				builder = builder.header("Vary", "Accept-Encoding");
+			}
+			return Ok(builder.status(304).body(full(Bytes::new())));
+		}
+		if let Some(etag) = &etag {
+			builder = builder.header("ETag", etag);
+		}
+	}
+	let ranges = if status == 200 && req.method == Method::GET {
+		range::requested(&req.headers, len, etag.as_deref(), modified)
+	} else {
+		Ranges::Full
+	};
+	match ranges {
+		Ranges::Full => {
+			f.rewind().await.map_err(|e| {
+				InternalError(
+					500,
+					format!("Unable to rewind to start of file while resolving to response: {}", e),
+				)
+			})?;
+			// HEAD only needs the length, which the metadata has
+			let body = if req.method == Method::HEAD {
+				full(Bytes::new())
+			} else {
+				StreamBody::new(ReaderStream::new(f.take(len)).map_ok(Frame::data)).boxed_unsync()
+			};
+			Ok(builder
+				.status(status)
+				.header("Content-Type", mimetype)
+				.header("Content-Length", len)
+				.body(body))
+		}
+		Ranges::Unsatisfiable => Ok(error_response(416).map(|mut r| {
+			r.headers_mut().insert(
+				"Content-Range",
+				format!("bytes */{}", len).parse().expect("content range is a valid header")
+			);
+			r
+		})),
+		Ranges::Partial(ranges) if ranges.len() == 1 => {
+			let range = ranges[0].clone();
+			Ok(builder
+				.status(206)
+				.header("Content-Type", mimetype)
+				.header("Content-Range", range::content_range(&range, len))
+				.header("Content-Length", range_len(&range))
+				.body(StreamBody::new(read_range(f, range).map_ok(Frame::data)).boxed_unsync()))
+		}
+		Ranges::Partial(ranges) => {
+			let boundary = range::boundary();
+			let mut parts = Vec::new();
+			let mut total = 0;
+			for r in ranges {
+				let header = range::part_header(&boundary, &mimetype, &r, len);
+				total += header.len() as u64 + range_len(&r);
+				// the parts are sent one after another, so they can share the file
+				let shared = f.try_clone().await.map_err(read_error)?;
+				parts.push(stream::iter([Ok(Bytes::from(header))]).chain(read_range(shared, r)));
+			}
+			let closing = range::closing_boundary(&boundary);
+			total += closing.len() as u64;
+			let body = stream::iter(parts)
+				.flatten()
+				.chain(stream::iter([Ok(Bytes::from(closing))]))
+				.map_ok(Frame::data);
+			Ok(builder
+				.status(206)
+				.header("Content-Type", format!("multipart/byteranges; boundary={}", boundary))
+				.header("Content-Length", total)
+				.body(StreamBody::new(body).boxed_unsync()))
+		}
+	}
+}
+
+/// Runs until the deadline, if there is one.  `None` if it ran out.
+async fn within<F: Future>(deadline: Option<Instant>, f: F) -> Option<F::Output> {
+	match deadline {
+		Some(deadline) => tokio::time::timeout_at(deadline, f).await.ok(),
+		None => Some(f.await),
+	}
+}
+
+/// Sends an error from a process in a chain back through the routing, to the
+/// error handlers above where it came from.
+async fn reroute(
+	mut origin: PathBuf,
+	code: u16,
+	basepath: &Path,
+	params: &[String],
+	layers: &[String],
+	ctx: &Context<'_>
+) -> ProcessingState {
+	origin.pop();
+	// special folders can bloat the path, might cause an infinite loop of errors.
+	// remove special folders from the path.
+	for _ in origin
+		.clone()
+		.iter()
+		.rev()
+		.map_while(|s| s.to_str().filter(|p| SPECIAL_FOLDERS.contains(p)))
+	{
+		origin.pop();
+	}
+	let len = origin.components().count()
+		.saturating_sub(basepath.components().count());
+	inner(handle_layer(
+		&mut basepath.to_path_buf(),
+		// only situation min statement should be useful is when something came from an
+		// index or error file.
+		&layers[..len.clamp(0, layers.len())],
+		&mut params.to_vec(),
+		ErrorCode(code),
+		&Settings::default(),
+		ctx
+	).await)
+}
+
+/// Kills a chain that ran past its deadline, and answers with a 504 from
+/// where the first process that was still running came from.
+async fn timed_out(
+	chain: &mut [OriginWrap<Process>],
+	basepath: &Path,
+	params: &[String],
+	layers: &[String],
+	ctx: &Context<'_>
+) -> ProcessingState {
+	let mut origin = None;
+	for child in chain.iter_mut() {
+		if origin.is_none() && child.data.child.try_wait().is_ok_and(|s| s.is_none()) {
+			origin = Some(child.origin.clone());
+		}
+		child.data.kill();
+	}
+	let Some(origin) = origin.or_else(|| chain.last().map(|c| c.origin.clone())) else {
+		return ErrorCode(504);
+	};
+	log!(error "TIMEOUT"; "{} timed out", origin.display());
+	reroute(origin, 504, basepath, params, layers, ctx).await
+}
+
+async fn resolve_to_response_inner(
 	status: ProcessingState,
-	basepath: &PathBuf,
-	params: &Vec<String>,
-	layers: &[String]
-) -> Result<Result<Response<Full<Bytes>>, Error>, ProcessingState> {
+	basepath: &Path,
+	params: &[String],
+	layers: &[String],
+	ctx: &Context<'_>
+) -> Result<Result<Response<ResponseBody>, Error>, ProcessingState> {
+	let req = ctx.req;
 	match status {
 		ErrorCode(e) => Ok(error_response(e)),
 		InternalError(e, msg) => {
@@ -418,127 +1751,125 @@ fn resolve_to_response_inner(
 		}
 		Static(HasStatus {
 			data: OriginWrap {
-				data: mut f,
+				data: f,
 				origin: p,
 			},
 			status,
-		}) => {
-			let mut data = Vec::new();
-			f.rewind().map_err(|e| {
-				InternalError(
-					500,
-					format!("Unable to rewind to start of file while resolving to response: {}", e),
-				)
-			})?;
-			f.read_to_end(&mut data)
-			 .map_err(|e| InternalError(500, format!("Couldn't read file {}: {}", p.display(), e)))?;
-			let mimetype = run_fun!(file -ib $p).map_err(|e| {
-				InternalError(500, format!("Error getting mimetype of {}: {}", p.display(), e))
-			})?;
-			Ok(Builder::new()
-				.status(status)
-				.header("Content-Type", mimetype)
-				.header("Content-Length", data.len())
-				.body(Full::new(Bytes::from(data))))
-		}
+		}) => resolve_static(f, p, status, ctx).await,
 		HttpError(e) => Ok(Err(e)),
+		RequestBody(_) => Err(
+			InternalError(500, "Incoming body was never handled".to_string())
+		),
+		Remote(HasStatus {
+			data: OriginWrap {
+				data: RemoteResponse { headers, body },
+				..
+			},
+			status,
+		}) => Ok(headers
+			.iter()
+			.fold(Builder::new().status(status), |b, (k, v)| b.header(k, v))
+			.body(body)),
 		Chain(HasStatus { data: mut c, status }) => {
-			let mut error: Option<(PathBuf, u16)> = None;
-			for OriginWrap {
-				data: child,
-				origin,
-			} in c.iter_mut()
-			{
-				if error.is_none() {
-					let status_data = child.wait().map_err(|e| {
-						InternalError(
-							500,
-							format!("Error resolving process chain at {}: {}", origin.display(), e),
-						)
-					})?;
-					let code = to_exit_code(status_data.code());
-					if status_is_ok(code) {
-						continue;
-					}
-					error = Some((origin.clone(), code))
+			let last = c
+				.last_mut()
+				.ok_or(InternalError(500, "Resolving empty chain".to_string()))?;
+			let origin = last.origin.clone();
+			let cgi = last.data.cgi;
+			let (Some(mut stdout), stderr) = (last.data.child.stdout.take(), last.data.child.stderr.take()) else {
+				return Err(InternalError(500, "End of chain has no output to capture".to_string()));
+			};
+			let deadline = ctx.take_deadline();
+			let mut buffered = Vec::new();
+			let block = within(deadline, async {
+				if cgi {
+					// scripts always end their headers before the body
+					let (headers, status) = cgi::read_headers(&mut stdout, &mut buffered)
+						.await
+						.map_err(|e| InternalError(500, format!("Bad output from {}: {}", origin.display(), e)))?;
+					Ok((headers, true, status))
 				} else {
-					let _ = child.kill();
+					let stderr = stderr.ok_or(
+						InternalError(500, "End of chain has no headers to capture".to_string())
+					)?;
+					let (headers, streaming) = read_header_block(stderr, &mut stdout, &mut buffered, &origin).await?;
+					Ok((headers, streaming, None))
 				}
-			}
-			if let Some((mut origin, code)) = error {
-				origin.pop();
-				// special folders can bloat the path, might cause an infinite loop of errors.
-				// remove special folders from the path.
-				for _ in origin
-					.clone()
-					.iter()
-					.rev()
-					.map_while(|s| s.to_str().filter(|p| SPECIAL_FOLDERS.contains(p)))
-				{
-					origin.pop();
+			}).await;
+			let Some(block) = block else {
+				return Err(timed_out(&mut c, basepath, params, layers, ctx).await);
+			};
+			let (headers, streaming, script_status) = block?;
+			let status = script_status.unwrap_or(status);
+			// executables opt into conditional requests by giving an ETag
+			let conditional = status == 200 && [Method::GET, Method::HEAD].contains(&req.method);
+			if conditional
+				&& streaming
+				&& let Some(headers) = revalidated(&headers, None, ctx)
+			{
+				for child in &mut c {
+					child.data.kill();
 				}
-				let len = origin.components().count()
-					.saturating_sub(basepath.components().count());
-				resolve_to_response_inner(
-					inner(handle_layer(
-						&mut basepath.clone(),
-						// only situation min statement should be useful is when something came from an
-						// index or error file. 
-						&layers[..len.clamp(0, layers.len())],
-						&mut params.clone(),
-						ErrorCode(code)
-					)),
-					basepath,
-					params,
-					layers
-				)
+				return Ok(not_modified_response(headers));
+			}
+			let builder = headers
+				.iter()
+				.fold(
+					Builder::new()
+						.status(status),
+					|b, (k, v)| b.header(k, v)
+				);
+			if streaming {
+				return Ok(builder.body(chain_body(buffered, stdout, c, deadline)));
+			}
+			let finished = within(deadline, async {
+				stdout.read_to_end(&mut buffered).await.map_err(
+					|e| InternalError(500, format!("End of chain could not capture output: {}", e))
+				)?;
+				wait_chain(&mut c).await
+			}).await;
+			let Some(finished) = finished else {
+				return Err(timed_out(&mut c, basepath, params, layers, ctx).await);
+			};
+			if let Some((origin, code)) = finished? {
+				Err(reroute(origin, code, basepath, params, layers, ctx).await)
+			} else if let Some(headers) = revalidated(&headers, Some(buffered.len() as u64), ctx)
+				.filter(|_| conditional)
+			{
+				Ok(not_modified_response(headers))
 			} else {
-				let last = c
-					.pop()
-					.ok_or(InternalError(500, "Resolving empty chain".to_string()))?;
-				let output = last
-					.data
-					.wait_with_output()
-					.map_err(
-						|e| InternalError(500, format!("End of chain could not capture output: {}", e))
-					)?;
-				Ok(String::from_utf8(output.stderr)
-					.map_err(
-						|e| InternalError(
-							500,
-							format!("Error reading utf-8 from header output: {}", e)
-						)
-					)?
-					.split("\n")
-					.filter_map(|s| s.split_once("="))
-					.fold(
-						Builder::new()
-							.status(status),
-						|b, (k, v)| b.header(k, v)
-					)
-					.header("Content-Length", output.stdout.len())
-					.body(output.stdout.into()))
+				Ok(builder.body(full(buffered)))
 			}
 		}
 	}
 }
 
-fn resolve_to_response(
-	status: ProcessingState,
+async fn resolve_to_response(
+	mut status: ProcessingState,
 	basepath: PathBuf,
-	params: &Vec<String>,
-	layers: &[String]
-) -> Result<Response<Full<Bytes>>, Error> {
-	match resolve_to_response_inner(status, &basepath, params, layers) {
-		Ok(o) => o,
-		Err(e) => resolve_to_response(e, basepath, params, layers),
+	params: &[String],
+	layers: &[String],
+	ctx: &Context<'_>
+) -> Result<Response<ResponseBody>, Error> {
+	// errors in a process chain are re-routed to error handlers, which need to
+	// be resolved in turn.  Those can fail again, this many is surely a loop.
+	for _ in 0..MAX_REROUTES {
+		match resolve_to_response_inner(status, &basepath, params, layers, ctx).await {
+			Ok(o) => return o,
+			Err(e) => status = e,
+		}
 	}
+	status.halt_processing();
+	log!(error "ERROR"; "Too many errors from error handlers, last one {}", status.status());
+	error_response(status.status())
 }
 
 /// uri_path, METHOD "" headers "" url parameters "" path parameters (server does not get fragment)
-/// (parameters, layers)
-fn get_params_and_layers(parts: http::request::Parts) -> (Vec<String>, Vec<String>) {
-	(
+/// (parameters, layers), `None` if the path or query can't be decoded
+fn get_params_and_layers(parts: &Parts) -> Option<(Vec<String>, Vec<String>)> {
+	let query = decode::query_params(parts.uri.query().unwrap_or(""))?;
+	let layers = decode::path_segments(parts.uri.path())?;
+	Some((
 		[
 			String::from(parts.uri.path()),
 			parts.method.to_string(),
@@ -548,6 +1879,7 @@ fn get_params_and_layers(parts: http::request::Parts) -> (Vec<String>, Vec<Strin
 			.chain(
 				parts
 					.headers
+					.clone()
 					.into_iter()
 					.filter_map(
 						|(name_opt, val)|
@@ -570,45 +1902,63 @@ fn get_params_and_layers(parts: http::request::Parts) -> (Vec<String>, Vec<Strin
 					.filter(|s| !s.is_empty())
 			)
 			.chain(["".to_string()])
-			.chain(
-				parts
-					.uri
-					.query()
-					.unwrap_or("")
-					.split("&")
-					.filter(|s| !s.is_empty())
-					.map(String::from)
-			)
+			.chain(query)
 			.chain(["".to_string()])
 			.collect::<Vec<String>>(),
-		parts.uri.path().split("/").filter(|p| !p.is_empty()).map(String::from).collect::<Vec<String>>()
-	 )
+		layers
+	 ))
 }
 
-async fn serve_help(body: Incoming, path: PathBuf, params: &[String], layers: &[String]) -> ProcessingState {
+async fn serve_help(
+	body: Incoming,
+	path: PathBuf,
+	params: &[String],
+	layers: &[String],
+	ctx: &Context<'_>
+) -> ProcessingState {
 	// get the path
 	let mut path = path.clone();
 
 	let mut params = Vec::from(params);
-	
+
+	// the body is only replayable for the buffered version.  Static files and
+	// error handlers never read the body anyways.
+	if ctx.config.stream_request_body {
+		return inner(handle_layer(
+			&mut path,
+			layers,
+			&mut params,
+			RequestBody(HasStatus {
+				data: OriginWrap {
+					data: body,
+					origin: "incoming".into(),
+				},
+				status: 200,
+			}),
+			&Settings::default(),
+			ctx
+		).await);
+	}
+
 	// open tempfile for input data and put it in
-	let Ok(mut inp) = tempfile() else {
+	let Ok(Ok(inp)) = spawn_blocking(tempfile).await else {
 		return InternalError(500, "Unable to create tempfile for buffer.".to_string());
 	};
+	let mut inp = File::from_std(inp);
 	let Ok(body_data) = body.collect().await else {
 		return InternalError(500, "Unable to collect entire incoming body.".to_string());
 	};
 	let bytes = body_data.to_bytes();
-	let Ok(_) = inp.write_all(&bytes) else {
+	let Ok(_) = inp.write_all(&bytes).await else {
 		return InternalError(
 			500,
 			"Unable to write incoming body to temp file.".to_string(),
 		);
 	};
-	let Ok(_) = inp.flush() else {
+	let Ok(_) = inp.flush().await else {
 		return InternalError(500, "Unable to flush temp file.".to_string());
 	};
-	let Ok(_) = inp.rewind() else {
+	let Ok(_) = inp.rewind().await else {
 		return InternalError(500, "Unable to rewind temp file.".to_string());
 	};
 	// handle it, then go over the output
@@ -618,24 +1968,65 @@ async fn serve_help(body: Incoming, path: PathBuf, params: &[String], layers: &[
 		&mut params,
 		Static(HasStatus {
 			data: OriginWrap {
-				data: inp,
+				data: inp.into(),
 				origin: "incoming".into(),
 			},
 			status: 200,
 		}),
-	))
+		&Settings::default(),
+		ctx
+	).await)
 }
 
-pub async fn serve(req: Request<Incoming>, path: PathBuf) -> Result<Response<Full<Bytes>>, Error> {
-	let (parts, body) = req.into_parts();
-	let (params, layers) = get_params_and_layers(parts);
+pub async fn serve(
+	req: Request<Incoming>,
+	path: PathBuf,
+	config: Arc<Config>,
+	peer: Peer
+) -> Result<Response<ResponseBody>, Error> {
+	let (mut parts, body) = req.into_parts();
+	let upgrade = parts.extensions.remove::<OnUpgrade>();
+	let host = vhost::request_host(&parts);
+	let Some(path) = config.hosts.root(&path, host.as_deref()).await else {
+		return error_response(421);
+	};
+	let Some((params, layers)) = get_params_and_layers(&parts) else {
+		return error_response(400);
+	};
+	let ctx = Context {
+		req: &parts,
+		config: &config,
+		base: &path,
+		headers: Mutex::default(),
+		captures: Mutex::default(),
+		deadline: Mutex::default(),
+		pre_processed: Mutex::default(),
+		aliases: AtomicUsize::new(0),
+		peer,
+		path_info: Mutex::default(),
+		upgrade: Mutex::new(upgrade),
+	};
 	let mut resp = resolve_to_response(
-		serve_help(body, path.clone(), &params, &layers).await,
-		path,
+		serve_help(body, path.clone(), &params, &layers, &ctx).await,
+		path.clone(),
 		&params,
-		&layers
-	)?;
-	if let Some(size) = resp.size_hint().exact() {
+		&layers,
+		&ctx
+	).await?;
+	let extra = ctx.headers.into_inner().unwrap();
+	for name in extra.keys() {
+		if !resp.headers().contains_key(name) {
+			for value in extra.get_all(name) {
+				resp.headers_mut().append(name, value.clone());
+			}
+		}
+	}
+	let mut resp = compress::compress(resp, &parts.headers, &config);
+	// these never have a body, so there's no length to give
+	let bodiless = resp.status().is_informational() || [204, 304].contains(&resp.status().as_u16());
+	// a HEAD response keeps the length its body would have had
+	let counted = parts.method == Method::HEAD && resp.headers().contains_key(CONTENT_LENGTH);
+	if let Some(size) = resp.size_hint().exact().filter(|_| !bodiless && !counted) {
 		resp.headers_mut().insert("Content-Length", size.into());
 	}
 	Ok(resp)
@@ -0,0 +1,283 @@
+//! Per-directory settings, from an optional `.config` file.
+//!
+//! Settings apply to the directory they're in and everything under it.  A
+//! deeper `.config` only overrides what it sets: headers and MIME types are
+//! merged by name, anything else replaces what was inherited.
+//!
+//! ```toml
+//! # seconds a handler can run for, instead of the server-wide `--timeout`
+//! timeout = 30
+//! # methods that are allowed at all, anything else gets a 405
+//! methods = ["GET", "POST"]
+//! # `Cache-Control` for responses
+//! cache = "public, max-age=3600"
+//! # list directories that have no handler
+//! listing = true
+//! # run handlers as CGI/1.1 scripts
+//! cgi = true
+//!
+//! [headers]
+//! X-Frame-Options = "DENY"
+//!
+//! # timeouts for particular handlers, by file name
+//! [timeouts]
+//! ".POST" = 120
+//!
+//! # same as a `.mime` file, by file name or by extension
+//! [mime]
+//! ".md" = "text/markdown; charset=utf-8"
+//!
+//! # keep handlers running between requests, see `workers`
+//! [workers]
+//! size = 4
+//! idle_timeout = 300
+//! restart = true
+//!
+//! # served with a 200 for paths that don't exist, for single page apps.
+//! # Only to requests that accept HTML, unless `any_accept` is set.
+//! [fallback]
+//! file = "index.html"
+//! any_accept = false
+//! ```
+//!
+//! The fallback file is relative to the directory of the `.config` that sets
+//! it, and has to be inside of it.
+
+use std::{
+	collections::BTreeMap,
+	path::{Component, Path, PathBuf},
+	time::Duration,
+};
+
+use hyper::{
+	header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CACHE_CONTROL},
+	Method,
+};
+use serde::Deserialize;
+
+use crate::compress;
+
+/// name of the per-directory settings file
+pub const CONFIG_FILE: &str = ".config";
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+	headers: BTreeMap<String, String>,
+	mime: BTreeMap<String, String>,
+	timeout: Option<f64>,
+	timeouts: BTreeMap<String, f64>,
+	methods: Option<Vec<String>>,
+	cache: Option<String>,
+	listing: Option<bool>,
+	cgi: Option<bool>,
+	workers: Option<Workers>,
+	fallback: Option<Fallback>,
+}
+
+/// How persistent workers of a handler are kept.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workers {
+	#[serde(default = "Workers::default_size")]
+	size: usize,
+	idle_timeout: Option<f64>,
+	#[serde(default = "Workers::default_restart")]
+	restart: bool,
+}
+
+impl Workers {
+	fn default_size() -> usize {
+		1
+	}
+
+	fn default_restart() -> bool {
+		true
+	}
+
+	/// How many workers of a handler can run at once.
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	/// How long a worker can be idle before it's stopped.
+	pub fn idle_timeout(&self) -> Option<Duration> {
+		self.idle_timeout.and_then(|t| Duration::try_from_secs_f64(t).ok())
+	}
+
+	/// Whether a worker that crashed is started again.
+	pub fn restart(&self) -> bool {
+		self.restart
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fallback {
+	file: PathBuf,
+	#[serde(default)]
+	any_accept: bool,
+}
+
+// whether the client would take an HTML page
+fn accepts_html(headers: &HeaderMap) -> bool {
+	headers
+		.get_all(ACCEPT)
+		.iter()
+		.filter_map(|a| a.to_str().ok())
+		.flat_map(compress::weighted)
+		.any(|(media, q)| {
+			q > 0.0 && ["text/html", "application/xhtml+xml"].iter().any(|m| media.eq_ignore_ascii_case(m))
+		})
+}
+
+/// The value of an `Allow` header for these methods.  HEAD goes along with
+/// GET.
+pub fn allow(methods: impl IntoIterator<Item = String>) -> String {
+	let mut allowed = methods
+		.into_iter()
+		.map(|m| m.to_ascii_uppercase())
+		.collect::<Vec<String>>();
+	if allowed.iter().any(|m| m == "GET") {
+		allowed.push("HEAD".to_string());
+	}
+	allowed.sort();
+	allowed.dedup();
+	allowed.join(", ")
+}
+
+impl Settings {
+	/// Parses and checks a `.config` file, so mistakes show up when it's
+	/// loaded instead of on some later request.
+	pub fn parse(content: &str) -> Result<Settings, String> {
+		let settings: Settings = toml::from_str(content).map_err(|e| e.to_string())?;
+		for (name, value) in &settings.headers {
+			HeaderName::from_bytes(name.as_bytes())
+				.map_err(|_| format!("invalid header name {:?}", name))?;
+			HeaderValue::from_str(value)
+				.map_err(|_| format!("invalid value for header {}", name))?;
+		}
+		if let Some(cache) = &settings.cache {
+			HeaderValue::from_str(cache).map_err(|_| "invalid cache policy".to_string())?;
+		}
+		for method in settings.methods.iter().flatten() {
+			Method::from_bytes(method.as_bytes())
+				.map_err(|_| format!("invalid method {:?}", method))?;
+		}
+		for timeout in settings.timeout.iter().chain(settings.timeouts.values()) {
+			if Duration::try_from_secs_f64(*timeout).is_err() {
+				return Err(format!("invalid timeout {}", timeout));
+			}
+		}
+		if let Some(workers) = &settings.workers {
+			if workers.size == 0 {
+				return Err("workers need a size of at least 1".to_string());
+			}
+			if let Some(idle) = workers.idle_timeout
+				&& Duration::try_from_secs_f64(idle).is_err()
+			{
+				return Err(format!("invalid idle timeout {}", idle));
+			}
+		}
+		if let Some(fallback) = &settings.fallback
+			&& !fallback.file.components().all(|c| matches!(c, Component::Normal(_)))
+		{
+			return Err(format!("fallback {} is not inside the directory", fallback.file.display()));
+		}
+		Ok(settings)
+	}
+
+	/// Makes paths relative to the directory of the `.config`, so they stay
+	/// right when inherited.
+	pub fn anchor(mut self, dir: &Path) -> Settings {
+		if let Some(fallback) = &mut self.fallback {
+			fallback.file = dir.join(&fallback.file);
+		}
+		self
+	}
+
+	/// These settings, with what a deeper directory sets on top.
+	pub fn merge(&self, child: &Settings) -> Settings {
+		let mut headers = self.headers.clone();
+		headers.extend(child.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+		let mut mime = self.mime.clone();
+		mime.extend(child.mime.iter().map(|(k, v)| (k.clone(), v.clone())));
+		let mut timeouts = self.timeouts.clone();
+		timeouts.extend(child.timeouts.iter().map(|(k, v)| (k.clone(), *v)));
+		Settings {
+			headers,
+			mime,
+			timeout: child.timeout.or(self.timeout),
+			timeouts,
+			methods: child.methods.clone().or_else(|| self.methods.clone()),
+			cache: child.cache.clone().or_else(|| self.cache.clone()),
+			listing: child.listing.or(self.listing),
+			cgi: child.cgi.or(self.cgi),
+			workers: child.workers.clone().or_else(|| self.workers.clone()),
+			fallback: child.fallback.clone().or_else(|| self.fallback.clone()),
+		}
+	}
+
+	/// Headers to add to responses, including `Cache-Control`.
+	pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+		let cache = self
+			.cache
+			.as_ref()
+			.and_then(|c| HeaderValue::from_str(c).ok())
+			.map(|c| (CACHE_CONTROL, c));
+		self.headers
+			.iter()
+			.filter_map(|(k, v)| Some((
+				HeaderName::from_bytes(k.as_bytes()).ok()?,
+				HeaderValue::from_str(v).ok()?
+			)))
+			.chain(cache)
+			.collect()
+	}
+
+	pub fn mime(&self) -> &BTreeMap<String, String> {
+		&self.mime
+	}
+
+	/// How long a handler can run for, by its own timeout if it has one, or
+	/// else the directory's.
+	pub fn timeout(&self, handler: &Path) -> Option<Duration> {
+		handler
+			.file_name()
+			.and_then(|name| name.to_str())
+			.and_then(|name| self.timeouts.get(name))
+			.or(self.timeout.as_ref())
+			.and_then(|t| Duration::try_from_secs_f64(*t).ok())
+	}
+
+	/// If a method isn't allowed, the ones that are, for an `Allow` header.
+	pub fn disallowed(&self, method: &Method) -> Option<String> {
+		let methods = self.methods.as_ref()?;
+		let allows = |m: &str| methods.iter().any(|a| a.eq_ignore_ascii_case(m));
+		if allows(method.as_str()) || method == Method::HEAD && allows("GET") {
+			return None;
+		}
+		Some(allow(methods.iter().cloned()))
+	}
+
+	pub fn listing(&self) -> bool {
+		self.listing.unwrap_or(false)
+	}
+
+	pub fn cgi(&self) -> bool {
+		self.cgi.unwrap_or(false)
+	}
+
+	pub fn workers(&self) -> Option<&Workers> {
+		self.workers.as_ref()
+	}
+
+	/// The file to serve in place of a 404, if this request can have it.
+	pub fn fallback(&self, method: &Method, headers: &HeaderMap) -> Option<&Path> {
+		let fallback = self.fallback.as_ref()?;
+		if ![Method::GET, Method::HEAD].contains(method) || !fallback.any_accept && !accepts_html(headers) {
+			return None;
+		}
+		Some(&fallback.file)
+	}
+}
@@ -0,0 +1,186 @@
+//! Virtual hosts, for serving several sites from one process.
+//!
+//! A request's host picks the folder it's served from.  A host given with
+//! `--host <name>=<folder>` gets that folder.  With `--vhost-dirs`, other
+//! hosts get the folder named after them in the base folder, like
+//! `base/example.com`.  Anything else is served as the `--default-host`, if
+//! there is one, and otherwise from the base folder itself.  With
+//! `--vhost-dirs` and no default, unknown hosts get a 421 instead, so other
+//! sites can't be reached as paths of the base folder.
+//!
+//! Over HTTPS, the certificate is picked the same way from the SNI name, out
+//! of those given with `--host-cert`, falling back to the main certificate.
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use http::request::Parts;
+use hyper::header::HOST;
+use rustls::{
+	server::{ClientHello, ResolvesServerCert},
+	sign::CertifiedKey,
+};
+
+use crate::routes;
+
+/// Splits a `<name>=<value>` command line argument.
+pub fn parse_mapping(arg: &str) -> Result<(String, String), String> {
+	let (name, value) = arg
+		.split_once("=")
+		.ok_or(format!("expected <name>=<value>, got {}", arg))?;
+	let name = normalize(name).ok_or(format!("{} is not a valid host name", name))?;
+	Ok((name, value.to_string()))
+}
+
+/// A host name without its port, in lowercase and without a trailing dot.
+pub fn normalize(host: &str) -> Option<String> {
+	let host = host.trim();
+	let name = match host.strip_prefix("[") {
+		// ipv6, which has colons of its own
+		Some(rest) => &host[..rest.find("]")? + 2],
+		None => host.split(":").next()?,
+	};
+	let name = name.trim_end_matches(".").to_ascii_lowercase();
+	(!name.is_empty()).then_some(name)
+}
+
+/// The host a request is for, from the target or the `Host` header.
+pub fn request_host(req: &Parts) -> Option<String> {
+	req.uri
+		.host()
+		.or_else(|| req.headers.get(HOST).and_then(|h| h.to_str().ok()))
+		.and_then(normalize)
+}
+
+// only plain host names can be folder names, nothing that could be a path
+fn is_dir_name(host: &str) -> bool {
+	!host.starts_with(".")
+		&& host.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-')
+}
+
+#[derive(Debug, Default)]
+pub struct Hosts {
+	roots: HashMap<String, PathBuf>,
+	dirs: bool,
+	default: Option<String>,
+}
+
+impl Hosts {
+	pub fn new(roots: HashMap<String, PathBuf>, dirs: bool, default: Option<String>) -> Self {
+		Hosts {
+			roots,
+			dirs,
+			default: default.as_deref().and_then(normalize),
+		}
+	}
+
+	async fn find(&self, base: &Path, host: &str) -> Option<PathBuf> {
+		if let Some(root) = self.roots.get(host) {
+			return Some(root.clone());
+		}
+		if self.dirs && is_dir_name(host) {
+			let dir = base.join(host);
+			if routes::lookup(&dir).await.is_some() {
+				return Some(dir);
+			}
+		}
+		None
+	}
+
+	/// The folder to serve a host from, `None` if it shouldn't be served.
+	pub async fn root(&self, base: &Path, host: Option<&str>) -> Option<PathBuf> {
+		if let Some(host) = host
+			&& let Some(root) = self.find(base, host).await
+		{
+			return Some(root);
+		}
+		match &self.default {
+			Some(default) => self.find(base, default).await,
+			None if self.dirs => None,
+			None => Some(base.to_path_buf()),
+		}
+	}
+}
+
+/// Picks a certificate by the SNI name of a connection.
+#[derive(Debug)]
+pub struct SniResolver {
+	certs: HashMap<String, Arc<CertifiedKey>>,
+	default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+	pub fn new(certs: HashMap<String, Arc<CertifiedKey>>, default: Arc<CertifiedKey>) -> Self {
+		SniResolver { certs, default }
+	}
+}
+
+impl ResolvesServerCert for SniResolver {
+	fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+		let cert = client_hello
+			.server_name()
+			.and_then(normalize)
+			.and_then(|name| self.certs.get(&name));
+		Some(cert.unwrap_or(&self.default).clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn normalized() {
+		for host in ["example.com", "Example.COM", "example.com.", "example.com:8080", " EXAMPLE.com.:443 "] {
+			assert_eq!(normalize(host).as_deref(), Some("example.com"), "{}", host);
+		}
+		assert_eq!(normalize("[::1]:8080").as_deref(), Some("[::1]"));
+		assert_eq!(normalize("[::1]").as_deref(), Some("[::1]"));
+		assert_eq!(normalize("127.0.0.1:80").as_deref(), Some("127.0.0.1"));
+		for host in ["", ":80", ".", "[::1"] {
+			assert_eq!(normalize(host), None, "{:?}", host);
+		}
+		let (name, root) = parse_mapping("Example.com.=/srv/a").unwrap();
+		assert_eq!((name.as_str(), root.as_str()), ("example.com", "/srv/a"));
+		assert!(parse_mapping("example.com").is_err());
+	}
+
+	#[test]
+	fn request_hosts() {
+		let parts = |uri: &str, host: &str| http::Request::get(uri).header(HOST, host).body(()).unwrap().into_parts().0;
+		assert_eq!(request_host(&parts("/", "Example.COM.:8080")).as_deref(), Some("example.com"));
+		// an absolute target wins over the header
+		assert_eq!(request_host(&parts("http://Other.org/", "example.com")).as_deref(), Some("other.org"));
+		assert_eq!(request_host(&parts("/", "")), None);
+	}
+
+	#[tokio::test]
+	async fn roots() {
+		let base = tempfile::tempdir().unwrap();
+		let base = base.path();
+		std::fs::create_dir(base.join("example.com")).unwrap();
+		std::fs::create_dir(base.join("fallback.org")).unwrap();
+		let mapped = PathBuf::from("/srv/mapped");
+		let roots = HashMap::from([("mapped.net".to_string(), mapped.clone())]);
+
+		let hosts = Hosts::new(roots.clone(), false, None);
+		assert_eq!(hosts.root(base, Some("mapped.net")).await, Some(mapped.clone()));
+		assert_eq!(hosts.root(base, Some("example.com")).await, Some(base.to_path_buf()));
+		assert_eq!(hosts.root(base, None).await, Some(base.to_path_buf()));
+
+		let hosts = Hosts::new(roots.clone(), true, None);
+		assert_eq!(hosts.root(base, Some("mapped.net")).await, Some(mapped.clone()));
+		assert_eq!(hosts.root(base, Some("example.com")).await, Some(base.join("example.com")));
+		// nothing else of the base folder can be reached
+		assert_eq!(hosts.root(base, Some("unknown.com")).await, None);
+		assert_eq!(hosts.root(base, Some("..")).await, None);
+		assert_eq!(hosts.root(base, None).await, None);
+
+		let hosts = Hosts::new(roots, true, Some("Fallback.org.:80".to_string()));
+		assert_eq!(hosts.root(base, Some("unknown.com")).await, Some(base.join("fallback.org")));
+		assert_eq!(hosts.root(base, None).await, Some(base.join("fallback.org")));
+	}
+}
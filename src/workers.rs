@@ -0,0 +1,343 @@
+//! Persistent workers, for handlers that take too long to start to run one
+//! for every request.
+//!
+//! With a `[workers]` table in a `.config`, handlers under it are started
+//! once and kept running, up to `size` of each at a time.  A worker answers
+//! one request after another, reading them from stdin and writing the
+//! answers to stdout as netstrings (`<length>:<bytes>,`):
+//!
+//! - a request is three netstrings: the arguments an executable would get,
+//!   each in a netstring of its own, then `PARAM_<name>=<value>` for named
+//!   captures the same way, and then the body.
+//! - an answer is three netstrings too: the status as a number, the headers,
+//!   each a `name=value` netstring, and then the body.  Answers are held in
+//!   memory, so none of them can be longer than 64 MiB.
+//!
+//! A worker idle for longer than `idle_timeout` seconds is stopped.  One that
+//! fails in the middle of a request is killed, and is started again when it's
+//! needed unless `restart = false`, in which case the handler does with fewer
+//! workers, and answers with a 503 when it has none left.  Workers are also
+//! started anew when their handler or its settings change.
+//!
+//! Like CGI, this is only for handlers, hooks and error handlers still run
+//! once per request.  What workers write to stderr goes to the log.
+
+use std::{
+	collections::HashMap,
+	io,
+	path::{Path, PathBuf},
+	process::Stdio,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, LazyLock, Mutex, Weak,
+	},
+	time::{Duration, SystemTime},
+};
+
+use tokio::{
+	io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	process::{Child, ChildStdin, ChildStdout, Command},
+	sync::{Semaphore, SemaphorePermit},
+	time::Instant,
+};
+
+use crate::{cgi, log, settings::Workers};
+
+// the longest netstring a worker can answer with, as answers are kept in
+// memory
+const MAX_NETSTRING: usize = 64 * 1024 * 1024;
+
+// pools by handler
+static POOLS: LazyLock<Mutex<HashMap<PathBuf, Arc<Pool>>>> = LazyLock::new(Mutex::default);
+
+struct Worker {
+	// leads a process group of its own, killed whole when dropped
+	child: Child,
+	stdin: ChildStdin,
+	stdout: BufReader<ChildStdout>,
+	idle_since: Instant,
+}
+
+struct Pool {
+	file: PathBuf,
+	settings: Workers,
+	// of the handler, to notice when it's replaced
+	modified: Option<SystemTime>,
+	idle: Mutex<Vec<Worker>>,
+	// one per worker that can run
+	slots: Semaphore,
+	crashed: AtomicUsize,
+}
+
+/// What a worker answered.
+pub struct Answer {
+	pub status: u16,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn netstring(data: &[u8]) -> Vec<u8> {
+	let mut out = format!("{}:", data.len()).into_bytes();
+	out.extend(data);
+	out.push(b',');
+	out
+}
+
+fn netstrings<'a>(items: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+	items.into_iter().flat_map(netstring).collect()
+}
+
+async fn read_netstring(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Vec<u8>> {
+	let mut len = Vec::new();
+	// no length needs more digits than this
+	if (&mut *reader).take(21).read_until(b':', &mut len).await? == 0 {
+		return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "worker exited"));
+	}
+	let len = std::str::from_utf8(&len)
+		.ok()
+		.and_then(|l| l.strip_suffix(":"))
+		.and_then(|l| l.parse::<usize>().ok())
+		.ok_or(invalid("bad netstring length"))?;
+	if len > MAX_NETSTRING {
+		return Err(invalid("netstring is too long"));
+	}
+	let mut data = vec![0; len + 1];
+	reader.read_exact(&mut data).await?;
+	if data.pop() != Some(b',') {
+		return Err(invalid("netstring doesn't end in a comma"));
+	}
+	Ok(data)
+}
+
+// netstrings one after another, all in memory already
+fn split_netstrings(mut data: &[u8]) -> io::Result<Vec<&[u8]>> {
+	let mut items = Vec::new();
+	while !data.is_empty() {
+		let colon = data
+			.iter()
+			.position(|b| *b == b':')
+			.ok_or(invalid("bad netstring length"))?;
+		let len = std::str::from_utf8(&data[..colon])
+			.ok()
+			.and_then(|l| l.parse::<usize>().ok())
+			.ok_or(invalid("bad netstring length"))?;
+		let rest = &data[colon + 1..];
+		if rest.get(len) != Some(&b',') {
+			return Err(invalid("netstring doesn't end in a comma"));
+		}
+		items.push(&rest[..len]);
+		data = &rest[len + 1..];
+	}
+	Ok(items)
+}
+
+async fn exchange(worker: &mut Worker, request: &[u8]) -> io::Result<Answer> {
+	let Worker { stdin, stdout, .. } = worker;
+	// a big answer can't get stuck behind a big request this way
+	let (_, (status, headers, body)) = tokio::try_join!(
+		async {
+			stdin.write_all(request).await?;
+			stdin.flush().await
+		},
+		async {
+			io::Result::Ok((
+				read_netstring(stdout).await?,
+				read_netstring(stdout).await?,
+				read_netstring(stdout).await?,
+			))
+		}
+	)?;
+	let status = std::str::from_utf8(&status)
+		.ok()
+		.and_then(|s| s.trim().parse::<u16>().ok())
+		.filter(|s| (100..600).contains(s))
+		.ok_or(invalid("bad status"))?;
+	let headers = split_netstrings(&headers)?
+		.into_iter()
+		.filter_map(|h| {
+			let (k, v) = std::str::from_utf8(h).ok()?.split_once("=")?;
+			Some((k.to_string(), v.to_string()))
+		})
+		.collect();
+	Ok(Answer { status, headers, body })
+}
+
+fn start(file: &Path) -> io::Result<Worker> {
+	let mut child = Command::new(file)
+		.current_dir(file.parent().unwrap_or(Path::new("/")))
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true)
+		.process_group(0)
+		.spawn()?;
+	cgi::log_stderr(child.stderr.take(), file.to_path_buf(), "WORKER");
+	let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+		return Err(io::Error::other("no pipes to the worker"));
+	};
+	Ok(Worker {
+		child,
+		stdin,
+		stdout: BufReader::new(stdout),
+		idle_since: Instant::now(),
+	})
+}
+
+impl Worker {
+	// the whole group, so nothing it started in the background lives on
+	fn kill(&mut self) {
+		if let Some(pid) = self.child.id() {
+			// SAFETY: only sends a signal, to a group that's still ours as
+			// long as the child hasn't been waited for
+			unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+		}
+		let _ = self.child.start_kill();
+	}
+}
+
+impl Drop for Worker {
+	fn drop(&mut self) {
+		if self.child.try_wait().is_ok_and(|s| s.is_none()) {
+			self.kill();
+		}
+	}
+}
+
+impl Pool {
+	// an idle worker that's still running, or else a new one
+	fn take(&self) -> io::Result<Worker> {
+		let mut idle = self.idle.lock().unwrap();
+		while let Some(mut worker) = idle.pop() {
+			if worker.child.try_wait().is_ok_and(|s| s.is_none()) {
+				return Ok(worker);
+			}
+			log!(error "WORKER"; "{} exited while idle", self.file.display());
+		}
+		drop(idle);
+		start(&self.file)
+	}
+
+	// a worker that failed can't be trusted with another request, and
+	// without restarts its slot goes with it
+	fn crash(&self, slot: SemaphorePermit<'_>) {
+		if self.settings.restart() {
+			return;
+		}
+		slot.forget();
+		if self.crashed.fetch_add(1, Ordering::Relaxed) + 1 >= self.settings.size() {
+			self.slots.close();
+		}
+	}
+}
+
+// stops workers that have been idle for too long
+fn reap(pool: Weak<Pool>, idle: Duration) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval((idle / 2).max(Duration::from_millis(100)));
+		loop {
+			interval.tick().await;
+			let Some(pool) = pool.upgrade() else {
+				return;
+			};
+			pool.idle.lock().unwrap().retain(|w| w.idle_since.elapsed() < idle);
+		}
+	});
+}
+
+fn pool(file: &Path, settings: &Workers, modified: Option<SystemTime>) -> Arc<Pool> {
+	let mut pools = POOLS.lock().unwrap();
+	if let Some(pool) = pools.get(file)
+		&& pool.settings == *settings
+		&& pool.modified == modified
+	{
+		return pool.clone();
+	}
+	// the old pool's workers stop once their requests are done
+	let pool = Arc::new(Pool {
+		file: file.to_path_buf(),
+		settings: settings.clone(),
+		modified,
+		idle: Mutex::default(),
+		slots: Semaphore::new(settings.size()),
+		crashed: AtomicUsize::new(0),
+	});
+	if let Some(idle) = settings.idle_timeout() {
+		reap(Arc::downgrade(&pool), idle);
+	}
+	pools.insert(file.to_path_buf(), pool.clone());
+	pool
+}
+
+/// Has a worker of `file` answer a request, waiting for one to be free if
+/// they're all busy.  Fails with the status to answer with instead.
+pub async fn handle(
+	file: &Path,
+	settings: &Workers,
+	modified: Option<SystemTime>,
+	args: &[String],
+	env: &[(String, String)],
+	body: &[u8],
+) -> Result<Answer, (u16, String)> {
+	let pool = pool(file, settings, modified);
+	let Ok(slot) = pool.slots.acquire().await else {
+		return Err((503, format!("All workers of {} crashed", file.display())));
+	};
+	let mut worker = pool
+		.take()
+		.map_err(|e| (500, format!("Could not start worker {}: {}", file.display(), e)))?;
+	let env = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>();
+	let mut request = netstring(&netstrings(args.iter().map(|a| a.as_bytes())));
+	request.extend(netstring(&netstrings(env.iter().map(|e| e.as_bytes()))));
+	request.extend(netstring(body));
+	match exchange(&mut worker, &request).await {
+		Ok(answer) => {
+			worker.idle_since = Instant::now();
+			pool.idle.lock().unwrap().push(worker);
+			Ok(answer)
+		}
+		Err(e) => {
+			// before it's waited for, while its group is still there to kill
+			worker.kill();
+			pool.crash(slot);
+			Err((502, format!("Worker {} failed: {}", file.display(), e)))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	async fn read(mut input: &[u8]) -> io::Result<Vec<u8>> {
+		read_netstring(&mut input).await
+	}
+
+	#[tokio::test]
+	async fn netstrings_round_trip() {
+		let data = netstrings([&b"one"[..], b"", b"three"]);
+		assert_eq!(data, b"3:one,0:,5:three,");
+		assert_eq!(split_netstrings(&data).unwrap(), [&b"one"[..], b"", b"three"]);
+		assert_eq!(read(&data).await.unwrap(), b"one");
+	}
+
+	#[tokio::test]
+	async fn bad_netstrings() {
+		assert_eq!(read(b"").await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+		assert_eq!(read(b"3:one;").await.unwrap_err().to_string(), "netstring doesn't end in a comma");
+		assert_eq!(read(b"x:one,").await.unwrap_err().to_string(), "bad netstring length");
+		assert_eq!(read(b"99999999999999999999999:").await.unwrap_err().to_string(), "bad netstring length");
+		assert!(split_netstrings(b"3:one").is_err());
+	}
+
+	#[tokio::test]
+	async fn long_netstring() {
+		let len = format!("{}:", MAX_NETSTRING + 1);
+		assert_eq!(read(len.as_bytes()).await.unwrap_err().to_string(), "netstring is too long");
+		// the length is checked before anything is read for it
+		assert_eq!(read(b"18446744073709551615:").await.unwrap_err().to_string(), "netstring is too long");
+	}
+}
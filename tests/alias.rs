@@ -0,0 +1,39 @@
+//! `.alias` serves another part of the tree in place of a directory.
+
+mod common;
+
+use common::{file, get, root, Server};
+
+#[tokio::test]
+async fn followed_with_captures() {
+	let root = root();
+	file(root.path(), "&(?P<name>[a-z]+)/.alias", "/files/${name}\n");
+	file(root.path(), "files/bob/page", "bob's page");
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/bob/page").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "bob's page");
+}
+
+#[tokio::test]
+async fn loops_are_cut_off() {
+	let root = root();
+	file(root.path(), "a/.alias", "/b\n");
+	file(root.path(), "b/.alias", "/a\n");
+	file(root.path(), "self/.alias", "/self\n");
+	// a chain that ends is followed however it starts
+	for i in 0..4 {
+		file(root.path(), &format!("chain{}/.alias", i), &format!("/chain{}\n", i + 1));
+	}
+	file(root.path(), "chain4/page", "end of the chain");
+	let server = Server::http(root).await;
+
+	for path in ["/a/page", "/b", "/self/page"] {
+		let (parts, _) = get(server.addr, path).await;
+		assert_eq!(parts.status, 508, "{}", path);
+	}
+	let (parts, body) = get(server.addr, "/chain0/page").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "end of the chain");
+}
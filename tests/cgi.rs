@@ -0,0 +1,112 @@
+//! CGI scripts get the request in meta-variables.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{file, get, root, script, Server};
+use http_body_util::Full;
+use hyper::{body::Bytes, Request};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+
+async fn server(scripts: &[(&str, &str)]) -> Server {
+	let root = root();
+	file(root.path(), "cgi/.config", "cgi = true\n");
+	for (name, content) in scripts {
+		script(root.path(), &format!("cgi/{}", name), content);
+	}
+	Server::http(root).await
+}
+
+#[tokio::test]
+async fn proxy_header_is_not_http_proxy() {
+	let server = server(&[(
+		"env",
+		"#!/bin/bash\nprintf 'Content-Type: text/plain\\n\\n'\necho \"proxy:${HTTP_PROXY-unset}\"\necho \"other:${HTTP_X_OTHER-unset}\"\n",
+	)])
+	.await;
+
+	let req = Request::get("/cgi/env")
+		.header("host", "localhost")
+		.header("proxy", "http://attacker.example:8080")
+		.header("x-other", "kept")
+		.body(Full::new(Bytes::new()))
+		.unwrap();
+	let (parts, body) = common::send(server.addr, req).await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "proxy:unset\nother:kept\n");
+}
+
+#[tokio::test]
+async fn status_and_location() {
+	let server = server(&[
+		("status", "#!/bin/bash\nprintf 'Status: 418 Short and stout\\nContent-Type: text/plain\\n\\nteapot'\n"),
+		("moved", "#!/bin/bash\nprintf 'Location: /elsewhere\\n\\n'\n"),
+		("moved-for-good", "#!/bin/bash\nprintf 'Status: 301\\nLocation: /elsewhere\\n\\n'\n"),
+		("bad-status", "#!/bin/bash\nprintf 'Status: soon\\n\\nbody'\n"),
+	])
+	.await;
+
+	let (parts, body) = get(server.addr, "/cgi/status").await;
+	assert_eq!(parts.status, 418);
+	assert!(!parts.headers.contains_key("status"));
+	assert_eq!(body, "teapot");
+	let (parts, _) = get(server.addr, "/cgi/moved").await;
+	assert_eq!(parts.status, 302);
+	assert_eq!(parts.headers["location"], "/elsewhere");
+	let (parts, _) = get(server.addr, "/cgi/moved-for-good").await;
+	assert_eq!(parts.status, 301);
+	assert_eq!(parts.headers["location"], "/elsewhere");
+	let (parts, body) = get(server.addr, "/cgi/bad-status").await;
+	assert_eq!(parts.status, 500);
+	assert_ne!(body, "body");
+}
+
+#[tokio::test]
+async fn path_info() {
+	let server = server(&[(
+		"split",
+		"#!/bin/bash\nprintf 'Content-Type: text/plain\\n\\n'\necho \"script:$SCRIPT_NAME\"\necho \"info:$PATH_INFO\"\n",
+	)])
+	.await;
+
+	for (path, script, info) in [
+		("/cgi/split", "/cgi/split", ""),
+		("/cgi/split/a", "/cgi/split", "/a"),
+		("/cgi/split/a/b%20c", "/cgi/split", "/a/b c"),
+	] {
+		let (parts, body) = get(server.addr, path).await;
+		assert_eq!(parts.status, 200, "{}", path);
+		assert_eq!(body, format!("script:{}\ninfo:{}\n", script, info), "{}", path);
+	}
+	// the rest of the path can't reach hidden files through a script
+	let (parts, _) = get(server.addr, "/cgi/split/.config").await;
+	assert_eq!(parts.status, 403);
+}
+
+#[tokio::test]
+async fn failure_cuts_response_short() {
+	// fails once what it wrote is on its way
+	let server = server(&[(
+		"fails",
+		"#!/bin/bash\nprintf 'Content-Type: text/plain\\n\\npartial'\nsleep 0.2\nexit 1\n",
+	)])
+	.await;
+
+	let mut stream = TcpStream::connect(server.addr).await.unwrap();
+	let request = "GET /cgi/fails HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: identity\r\n\r\n";
+	stream.write_all(request.as_bytes()).await.unwrap();
+	let mut response = Vec::new();
+	tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+		.await
+		.expect("the response never ended")
+		.unwrap();
+	let response = String::from_utf8_lossy(&response);
+	assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+	assert!(response.contains("partial"), "{}", response);
+	// no last chunk, so the client can tell it's incomplete
+	assert!(!response.ends_with("0\r\n\r\n"), "{}", response);
+}
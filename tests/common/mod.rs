@@ -0,0 +1,112 @@
+//! The server running on a folder of its own, for tests to talk to.
+
+// every test only uses some of this
+#![allow(dead_code)]
+
+use std::{
+	fs,
+	net::{SocketAddr, TcpListener},
+	os::unix::fs::PermissionsExt,
+	path::Path,
+	process::{Child, Command, Stdio},
+	time::Duration,
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+	body::Bytes,
+	client::conn::http1,
+	http::response::Parts,
+	Request,
+};
+use hyper_util::rt::TokioIo;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+pub struct Server {
+	child: Child,
+	pub addr: SocketAddr,
+	// deleted when the server is done with it
+	pub root: TempDir,
+}
+
+impl Server {
+	/// Serves `root` with the given arguments on top of the folder and
+	/// address, once it's accepting connections.
+	pub async fn start(root: TempDir, args: &[&str]) -> Server {
+		// the port is free as long as nothing else takes it in the meantime
+		let addr = TcpListener::bind("127.0.0.1:0")
+			.and_then(|l| l.local_addr())
+			.expect("no free port");
+		let child = Command::new(env!("CARGO_BIN_EXE_simple_serve"))
+			.arg(root.path())
+			.arg(addr.to_string())
+			.args(args)
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.expect("could not start the server");
+		let server = Server { child, addr, root };
+		for _ in 0..500 {
+			if TcpStream::connect(addr).await.is_ok() {
+				return server;
+			}
+			tokio::time::sleep(Duration::from_millis(20)).await;
+		}
+		panic!("server never started listening on {}", addr);
+	}
+
+	/// Serves `root` over plain HTTP.
+	pub async fn http(root: TempDir) -> Server {
+		Server::start(root, &["-H"]).await
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+/// An empty folder to serve.
+pub fn root() -> TempDir {
+	tempfile::tempdir().expect("could not create a folder to serve")
+}
+
+/// Writes a file under `root`, and the directories it's in.
+pub fn file(root: &Path, path: &str, content: &str) {
+	let path = root.join(path);
+	fs::create_dir_all(path.parent().unwrap()).unwrap();
+	fs::write(path, content).unwrap();
+}
+
+/// Writes an executable under `root`.
+pub fn script(root: &Path, path: &str, content: &str) {
+	file(root, path, content);
+	fs::set_permissions(root.join(path), fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Sends a request over a new HTTP/1.1 connection, and reads all of the
+/// response.
+pub async fn send(addr: SocketAddr, req: Request<Full<Bytes>>) -> (Parts, Bytes) {
+	let exchange = async {
+		let stream = TcpStream::connect(addr).await.unwrap();
+		let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+		tokio::spawn(connection);
+		let (parts, body) = sender.send_request(req).await.unwrap().into_parts();
+		(parts, body.collect().await.unwrap().to_bytes())
+	};
+	tokio::time::timeout(Duration::from_secs(20), exchange)
+		.await
+		.expect("no response in time")
+}
+
+/// GETs a path over a new HTTP/1.1 connection.
+pub async fn get(addr: SocketAddr, path: &str) -> (Parts, Bytes) {
+	let req = Request::get(path)
+		.header("Host", "localhost")
+		.body(Full::default())
+		.unwrap();
+	send(addr, req).await
+}
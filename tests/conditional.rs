@@ -0,0 +1,131 @@
+//! Clients that already have a response get a 304 in its place.
+
+mod common;
+
+use common::{file, get, root, script, send, Server};
+use http_body_util::Full;
+use hyper::{body::Bytes, http::response::Parts, Request};
+
+async fn server(args: &[&str]) -> Server {
+	let root = root();
+	file(root.path(), "page.txt", "some text");
+	file(root.path(), "cgi/.config", "cgi = true\n");
+	script(root.path(), "cgi/tagged", "#!/bin/bash\nprintf 'Content-Type: text/plain\\nETag: \"v1\"\\n\\ntagged'\n");
+	script(root.path(), "cgi/untagged", "#!/bin/bash\nprintf 'Content-Type: text/plain\\n\\nuntagged'\n");
+	Server::start(root, &[&["-H"], args].concat()).await
+}
+
+async fn conditional(server: &Server, method: &str, path: &str, header: &str, value: &str) -> (Parts, Bytes) {
+	let req = Request::builder()
+		.method(method)
+		.uri(path)
+		.header("Host", "localhost")
+		.header(header, value)
+		.body(Full::default())
+		.unwrap();
+	send(server.addr, req).await
+}
+
+#[tokio::test]
+async fn if_none_match() {
+	let server = server(&[]).await;
+
+	let (parts, _) = get(server.addr, "/page.txt").await;
+	let etag = parts.headers["etag"].to_str().unwrap().to_string();
+	assert!(etag.starts_with("W/"), "{}", etag);
+	for method in ["GET", "HEAD"] {
+		let (parts, body) = conditional(&server, method, "/page.txt", "If-None-Match", &etag).await;
+		assert_eq!(parts.status, 304, "{}", method);
+		assert_eq!(parts.headers["etag"], etag.as_str());
+		assert!(body.is_empty());
+	}
+	// any one of a list, and weak comparison ignores the prefix
+	let listed = format!("\"other\", {}", etag.trim_start_matches("W/"));
+	let (parts, _) = conditional(&server, "GET", "/page.txt", "If-None-Match", &listed).await;
+	assert_eq!(parts.status, 304);
+	let (parts, body) = conditional(&server, "GET", "/page.txt", "If-None-Match", "\"other\"").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "some text");
+	// only reads are answered from the client's copy
+	let (parts, _) = conditional(&server, "POST", "/page.txt", "If-None-Match", &etag).await;
+	assert_ne!(parts.status, 304);
+}
+
+#[tokio::test]
+async fn if_none_match_any() {
+	let server = server(&[]).await;
+
+	let (parts, _) = conditional(&server, "GET", "/page.txt", "If-None-Match", "*").await;
+	assert_eq!(parts.status, 304);
+	let (parts, _) = conditional(&server, "GET", "/cgi/untagged", "If-None-Match", "*").await;
+	assert_eq!(parts.status, 200);
+}
+
+#[tokio::test]
+async fn if_modified_since() {
+	let server = server(&[]).await;
+
+	let (parts, _) = get(server.addr, "/page.txt").await;
+	let modified = parts.headers["last-modified"].to_str().unwrap().to_string();
+	let (parts, body) = conditional(&server, "GET", "/page.txt", "If-Modified-Since", &modified).await;
+	assert_eq!(parts.status, 304);
+	assert!(body.is_empty());
+	let old = "Mon, 01 Jan 2001 00:00:00 GMT";
+	let (parts, body) = conditional(&server, "GET", "/page.txt", "If-Modified-Since", old).await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "some text");
+	// If-None-Match wins when both are there
+	let req = Request::get("/page.txt")
+		.header("Host", "localhost")
+		.header("If-Modified-Since", &modified)
+		.header("If-None-Match", "\"other\"")
+		.body(Full::default())
+		.unwrap();
+	let (parts, _) = send(server.addr, req).await;
+	assert_eq!(parts.status, 200);
+}
+
+#[tokio::test]
+async fn strong_tags() {
+	let server = server(&["--strong-etags"]).await;
+
+	let (parts, _) = get(server.addr, "/page.txt").await;
+	let etag = parts.headers["etag"].to_str().unwrap().to_string();
+	assert!(etag.starts_with("\""), "{}", etag);
+	// the same contents, the same tag
+	let (parts, _) = get(server.addr, "/page.txt").await;
+	assert_eq!(parts.headers["etag"], etag.as_str());
+	let (parts, _) = conditional(&server, "GET", "/page.txt", "If-None-Match", &etag).await;
+	assert_eq!(parts.status, 304);
+	// a weak copy of it still matches for a 304
+	let weak = format!("W/{}", etag);
+	let (parts, _) = conditional(&server, "GET", "/page.txt", "If-None-Match", &weak).await;
+	assert_eq!(parts.status, 304);
+}
+
+#[tokio::test]
+async fn executables() {
+	let server = server(&[]).await;
+
+	// nothing on disk says what an executable answers with
+	let (parts, body) = get(server.addr, "/cgi/untagged").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "untagged");
+	assert!(!parts.headers.contains_key("etag"));
+	assert!(!parts.headers.contains_key("last-modified"));
+	let modified = "Mon, 01 Jan 2091 00:00:00 GMT";
+	let (parts, _) = conditional(&server, "GET", "/cgi/untagged", "If-Modified-Since", modified).await;
+	assert_eq!(parts.status, 200);
+
+	// unless it says so itself
+	let (parts, body) = get(server.addr, "/cgi/tagged").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(parts.headers["etag"], "\"v1\"");
+	assert_eq!(body, "tagged");
+	let (parts, body) = conditional(&server, "GET", "/cgi/tagged", "If-None-Match", "\"v1\"").await;
+	assert_eq!(parts.status, 304);
+	assert_eq!(parts.headers["etag"], "\"v1\"");
+	assert!(body.is_empty());
+	let (parts, _) = conditional(&server, "GET", "/cgi/tagged", "If-None-Match", "\"v0\"").await;
+	assert_eq!(parts.status, 200);
+}
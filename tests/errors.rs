@@ -0,0 +1,41 @@
+//! Errors from handlers go back to the `.error` handlers above where they
+//! came from.
+
+mod common;
+
+use common::{file, get, root, script, Server};
+
+#[tokio::test]
+async fn failed_handler_reaches_error_handler_in_its_directory() {
+	let root = root();
+	script(root.path(), "e/run", "#!/bin/bash\nexit $(printenv 404)\n");
+	file(root.path(), "e/.error/404", "custom 404 page");
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/e/run").await;
+	assert_eq!(parts.status, 404);
+	assert_eq!(body, "custom 404 page");
+}
+
+#[tokio::test]
+async fn failed_index_is_not_run_again() {
+	let root = root();
+	script(root.path(), "e/.index", "#!/bin/bash\nexit $(printenv 500)\n");
+	file(root.path(), "e/.error/500", "custom 500 page");
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/e").await;
+	assert_eq!(parts.status, 500);
+	assert_eq!(body, "custom 500 page");
+}
+
+#[tokio::test]
+async fn failing_error_handler_gives_up() {
+	let root = root();
+	script(root.path(), "e/.index", "#!/bin/bash\nexit $(printenv 404)\n");
+	script(root.path(), "e/.error/404", "#!/bin/bash\nexit $(printenv 404)\n");
+	let server = Server::http(root).await;
+
+	let (parts, _) = get(server.addr, "/e").await;
+	assert_eq!(parts.status, 404);
+}
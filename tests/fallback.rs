@@ -0,0 +1,72 @@
+//! Single page apps answer paths that don't exist with their page, but only
+//! to requests that want a page.
+
+mod common;
+
+use common::{file, root, script, send, Server};
+use http_body_util::Full;
+use hyper::{body::Bytes, Request};
+
+const PAGE: &str = "<html>app</html>";
+
+async fn server() -> Server {
+	let root = root();
+	file(root.path(), "app/.config", "[fallback]\nfile = \"index.html\"\n");
+	file(root.path(), "app/index.html", PAGE);
+	file(root.path(), "app/real.txt", "real file");
+	script(root.path(), "app/api/.index", "#!/bin/bash\necho api\n");
+	file(root.path(), "any/.config", "[fallback]\nfile = \"index.html\"\nany_accept = true\n");
+	file(root.path(), "any/index.html", PAGE);
+	Server::http(root).await
+}
+
+async fn accepting(server: &Server, method: &str, path: &str, accept: Option<&str>) -> (u16, Bytes) {
+	let mut req = Request::builder().method(method).uri(path).header("Host", "localhost");
+	if let Some(accept) = accept {
+		req = req.header("Accept", accept);
+	}
+	let (parts, body) = send(server.addr, req.body(Full::default()).unwrap()).await;
+	(parts.status.as_u16(), body)
+}
+
+const BROWSER: Option<&str> = Some("text/html,application/xhtml+xml;q=0.9,*/*;q=0.8");
+
+#[tokio::test]
+async fn pages_get_the_fallback() {
+	let server = server().await;
+
+	for path in ["/app/settings", "/app/users/42/profile"] {
+		let (status, body) = accepting(&server, "GET", path, BROWSER).await;
+		assert_eq!(status, 200, "{}", path);
+		assert_eq!(body, PAGE, "{}", path);
+	}
+	// what exists still wins
+	assert_eq!(accepting(&server, "GET", "/app/real.txt", BROWSER).await, (200, Bytes::from("real file")));
+	assert_eq!(accepting(&server, "GET", "/app/api", BROWSER).await, (200, Bytes::from("api\n")));
+	// and only reads get it
+	assert_eq!(accepting(&server, "POST", "/app/settings", BROWSER).await.0, 404);
+}
+
+#[tokio::test]
+async fn others_get_404() {
+	let server = server().await;
+
+	for accept in [Some("application/json"), Some("*/*"), Some("text/html;q=0"), None] {
+		let (status, body) = accepting(&server, "GET", "/app/settings", accept).await;
+		assert_eq!(status, 404, "{:?}", accept);
+		assert_ne!(body, PAGE, "{:?}", accept);
+	}
+	// assets that are missing stay missing
+	let (status, body) = accepting(&server, "GET", "/app/missing.js", Some("*/*")).await;
+	assert_eq!(status, 404);
+	assert_ne!(body, PAGE);
+}
+
+#[tokio::test]
+async fn any_accept() {
+	let server = server().await;
+
+	let (status, body) = accepting(&server, "GET", "/any/settings", Some("application/json")).await;
+	assert_eq!(status, 200);
+	assert_eq!(body, PAGE);
+}
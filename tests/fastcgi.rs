@@ -0,0 +1,56 @@
+//! Requests under a `.fastcgi` go to the app, if they're allowed at all.
+
+mod common;
+
+use std::path::Path;
+
+use common::{file, get, root, send, Server};
+use http_body_util::Full;
+use hyper::Request;
+use tokio::{io::AsyncWriteExt, net::UnixListener};
+
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+	let mut record = vec![1, kind, 0, 1];
+	record.extend((content.len() as u16).to_be_bytes());
+	record.extend([0, 0]);
+	record.extend(content);
+	record
+}
+
+// answers every request with "app", without waiting for it
+fn app(socket: &Path) {
+	let listener = UnixListener::bind(socket).unwrap();
+	tokio::spawn(async move {
+		loop {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut answer = record(6, b"Content-Type: text/plain\r\n\r\napp");
+			answer.extend(record(6, b""));
+			answer.extend(record(3, &[0; 8]));
+			let _ = stream.write_all(&answer).await;
+		}
+	});
+}
+
+#[tokio::test]
+async fn disallowed_methods_are_not_passed_on() {
+	let root = root();
+	let socket = root.path().join("app.sock");
+	app(&socket);
+	file(root.path(), "php/.fastcgi", &format!("unix:{}\n", socket.display()));
+	file(root.path(), "php/.config", "methods = [\"GET\"]\n");
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/php/index.php").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "app");
+	for path in ["/php", "/php/index.php", "/php/deeper/index.php"] {
+		let req = Request::delete(path)
+			.header("Host", "localhost")
+			.body(Full::default())
+			.unwrap();
+		let (parts, body) = send(server.addr, req).await;
+		assert_eq!(parts.status, 405, "{}", path);
+		assert_eq!(parts.headers["allow"], "GET, HEAD", "{}", path);
+		assert_ne!(body, "app", "{}", path);
+	}
+}
@@ -0,0 +1,61 @@
+//! Hidden entries stay hidden however the path to them is encoded.
+
+mod common;
+
+use common::{file, get, root, Server};
+
+const SECRET: &str = "secret content";
+
+async fn server() -> Server {
+	let root = root();
+	file(root.path(), ".secret", SECRET);
+	file(root.path(), "&secret", SECRET);
+	file(root.path(), "sub/.secret", SECRET);
+	file(root.path(), "sub/public", "public content");
+	Server::http(root).await
+}
+
+#[tokio::test]
+async fn plain_paths() {
+	let server = server().await;
+
+	let (parts, body) = get(server.addr, "/sub/public").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "public content");
+	for path in ["/.secret", "/&secret", "/sub/.secret"] {
+		let (parts, body) = get(server.addr, path).await;
+		assert_eq!(parts.status, 403, "{}", path);
+		assert_ne!(body, SECRET, "{}", path);
+	}
+}
+
+#[tokio::test]
+async fn encoded_paths() {
+	let server = server().await;
+
+	for path in [
+		"/%2esecret",
+		"/%2Esecret",
+		"/%26secret",
+		"/sub/%2esecret",
+		"/sub/%2e%2e/%2esecret",
+		"/sub/%2E%2E/%26secret",
+		"/%2e%2e/%2esecret",
+		"/%2e/sub/%2e/%2esecret",
+	] {
+		let (parts, body) = get(server.addr, path).await;
+		assert_eq!(parts.status, 403, "{}", path);
+		assert_ne!(body, SECRET, "{}", path);
+	}
+}
+
+#[tokio::test]
+async fn encoded_slashes() {
+	let server = server().await;
+
+	for path in ["/sub%2F.secret", "/sub%2f..%2f.secret", "/a%2F..%2F.secret"] {
+		let (parts, body) = get(server.addr, path).await;
+		assert_eq!(parts.status, 400, "{}", path);
+		assert_ne!(body, SECRET, "{}", path);
+	}
+}
@@ -0,0 +1,244 @@
+//! HTTP/2 next to HTTP/1.1: negotiated with ALPN over TLS, and as h2c, by
+//! prior knowledge or an `Upgrade`, on the plain listener.
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{file, root, Server};
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+	body::Bytes,
+	client::conn::{http1, http2},
+	Request, Version,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// A server with a certificate for `localhost`, and a client config that
+/// trusts it and offers the given protocols.
+async fn tls_server(alpn: &[&[u8]]) -> (Server, ClientConfig) {
+	let root = root();
+	file(root.path(), "hello.txt", "hello");
+	let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+	let keys = tempfile::tempdir().unwrap();
+	let (cert, key) = (keys.path().join("cert.pem"), keys.path().join("key.pem"));
+	std::fs::write(&cert, certified.cert.pem()).unwrap();
+	std::fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+	let server = Server::start(root, &[
+		"-c", cert.to_str().unwrap(),
+		"-p", key.to_str().unwrap(),
+	]).await;
+
+	let mut roots = RootCertStore::empty();
+	roots.add(certified.cert.der().clone()).unwrap();
+	let mut config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+	config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+	(server, config)
+}
+
+async fn connect_tls(server: &Server, config: ClientConfig) -> TlsStream<TcpStream> {
+	let tcp = TcpStream::connect(server.addr).await.unwrap();
+	TlsConnector::from(Arc::new(config))
+		.connect(ServerName::try_from("localhost").unwrap(), tcp)
+		.await
+		.unwrap()
+}
+
+fn hello(uri: &str) -> Request<Empty<Bytes>> {
+	Request::get(uri)
+		.header("Host", "localhost")
+		.body(Empty::new())
+		.unwrap()
+}
+
+#[tokio::test]
+async fn alpn_h2() {
+	let (server, config) = tls_server(&[b"h2", b"http/1.1"]).await;
+	let tls = connect_tls(&server, config).await;
+	assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+	let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(tls))
+		.await
+		.unwrap();
+	tokio::spawn(connection);
+	let resp = sender.send_request(hello("https://localhost/hello.txt")).await.unwrap();
+	assert_eq!(resp.version(), Version::HTTP_2);
+	assert_eq!(resp.status(), 200);
+	assert_eq!(resp.into_body().collect().await.unwrap().to_bytes(), "hello");
+}
+
+#[tokio::test]
+async fn alpn_http1() {
+	let (server, config) = tls_server(&[b"http/1.1"]).await;
+	let tls = connect_tls(&server, config).await;
+	assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+	let (mut sender, connection) = http1::handshake(TokioIo::new(tls)).await.unwrap();
+	tokio::spawn(connection);
+	let resp = sender.send_request(hello("/hello.txt")).await.unwrap();
+	assert_eq!(resp.version(), Version::HTTP_11);
+	assert_eq!(resp.status(), 200);
+	assert_eq!(resp.into_body().collect().await.unwrap().to_bytes(), "hello");
+}
+
+#[tokio::test]
+async fn h2c_prior_knowledge() {
+	let root = root();
+	file(root.path(), "hello.txt", "hello");
+	let server = Server::http(root).await;
+
+	let tcp = TcpStream::connect(server.addr).await.unwrap();
+	let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(tcp))
+		.await
+		.unwrap();
+	tokio::spawn(connection);
+	let resp = sender.send_request(hello("http://localhost/hello.txt")).await.unwrap();
+	assert_eq!(resp.version(), Version::HTTP_2);
+	assert_eq!(resp.status(), 200);
+	assert_eq!(resp.into_body().collect().await.unwrap().to_bytes(), "hello");
+}
+
+// a frame header is the length, type, flags and stream
+async fn read_frame(tcp: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+	let mut header = [0; 9];
+	tcp.read_exact(&mut header).await.unwrap();
+	let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+	let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+	let mut payload = vec![0; len];
+	tcp.read_exact(&mut payload).await.unwrap();
+	(header[3], header[4], stream, payload)
+}
+
+// asks for an h2c upgrade with the given settings, and reads the head of
+// the answer
+async fn upgrade(tcp: &mut TcpStream, path: &str, settings: &str) -> String {
+	tcp.write_all(format!(
+		concat!(
+			"GET {} HTTP/1.1\r\n",
+			"Host: localhost\r\n",
+			"Connection: Upgrade, HTTP2-Settings\r\n",
+			"Upgrade: h2c\r\n",
+			"HTTP2-Settings: {}\r\n",
+			"\r\n",
+		),
+		path,
+		settings,
+	).as_bytes()).await.unwrap();
+	let mut head = Vec::new();
+	while !head.ends_with(b"\r\n\r\n") {
+		head.push(tcp.read_u8().await.unwrap());
+	}
+	String::from_utf8(head).unwrap()
+}
+
+#[tokio::test]
+async fn h2c_upgrade() {
+	let root = root();
+	file(root.path(), "hello.txt", "hello");
+	let server = Server::http(root).await;
+
+	let mut tcp = TcpStream::connect(server.addr).await.unwrap();
+	let head = upgrade(&mut tcp, "/hello.txt", "AAMAAABkAARAAAAAAAIAAAAA").await;
+	assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+	// the preface and an empty SETTINGS frame
+	tcp.write_all(PREFACE).await.unwrap();
+	tcp.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).await.unwrap();
+	// the request that asked for the upgrade is answered on stream 1
+	let answer = async {
+		let mut status = None;
+		let mut body = Vec::new();
+		loop {
+			let (kind, flags, stream, payload) = read_frame(&mut tcp).await;
+			match (kind, stream) {
+				// HEADERS, with `:status: 200` from the static table first
+				(1, 1) => status = payload.first().copied(),
+				// DATA
+				(0, 1) => body.extend(payload),
+				_ => continue,
+			}
+			// END_STREAM
+			if flags & 1 != 0 {
+				return (status, body);
+			}
+		}
+	};
+	let (status, body) = tokio::time::timeout(Duration::from_secs(10), answer).await.unwrap();
+	assert_eq!(status, Some(0x88));
+	assert_eq!(body, b"hello");
+}
+
+#[tokio::test]
+async fn h2c_upgrade_settings_apply() {
+	let root = root();
+	file(root.path(), "long.txt", "hello, this is longer");
+	let server = Server::http(root).await;
+
+	let mut tcp = TcpStream::connect(server.addr).await.unwrap();
+	// an initial window of 5 bytes
+	let head = upgrade(&mut tcp, "/long.txt", "AAQAAAAF").await;
+	assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+	tcp.write_all(PREFACE).await.unwrap();
+	tcp.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).await.unwrap();
+
+	let mut body = Vec::new();
+	let mut acks = 0;
+	let until_window = async {
+		while body.len() < 5 {
+			match read_frame(&mut tcp).await {
+				(0, _, 1, payload) => body.extend(payload),
+				(4, flags, 0, _) if flags & 1 != 0 => acks += 1,
+				_ => {}
+			}
+		}
+	};
+	tokio::time::timeout(Duration::from_secs(10), until_window).await.unwrap();
+	assert_eq!(body, b"hello");
+	// the client's own SETTINGS is the only one to acknowledge
+	assert_eq!(acks, 1);
+	// nothing more until the window opens
+	let more = tokio::time::timeout(Duration::from_millis(300), read_frame(&mut tcp)).await;
+	assert!(more.is_err(), "{:?}", more);
+
+	// WINDOW_UPDATE on stream 1
+	tcp.write_all(&[0, 0, 4, 8, 0, 0, 0, 0, 1, 0, 0, 1, 0]).await.unwrap();
+	let rest = async {
+		loop {
+			let (kind, flags, stream, payload) = read_frame(&mut tcp).await;
+			if (kind, stream) == (0, 1) {
+				body.extend(payload);
+				if flags & 1 != 0 {
+					return;
+				}
+			}
+		}
+	};
+	tokio::time::timeout(Duration::from_secs(10), rest).await.unwrap();
+	assert_eq!(body, b"hello, this is longer");
+}
+
+#[tokio::test]
+async fn h2c_upgrade_bad_settings() {
+	let root = root();
+	file(root.path(), "hello.txt", "hello");
+	let server = Server::http(root).await;
+
+	// not base64url, not whole settings, and a push setting that isn't 0 or 1
+	for settings in ["AAMA+/8", "AAMAAABkAA", "AAIAAAAC"] {
+		let mut tcp = TcpStream::connect(server.addr).await.unwrap();
+		let head = upgrade(&mut tcp, "/hello.txt", settings).await;
+		assert!(head.starts_with("HTTP/1.1 400"), "{}: {}", settings, head);
+	}
+}
@@ -0,0 +1,23 @@
+//! Generated listings are made fresh for each request.
+
+mod common;
+
+use common::{file, get, root, Server};
+
+#[tokio::test]
+async fn listing_has_no_validators() {
+	let root = root();
+	file(root.path(), ".config", "listing = true\n");
+	file(root.path(), "dir/a", "a");
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/dir/").await;
+	assert_eq!(parts.status, 200);
+	assert!(String::from_utf8_lossy(&body).contains("a"));
+	assert!(!parts.headers.contains_key("etag"));
+	assert!(!parts.headers.contains_key("last-modified"));
+
+	let (parts, _) = get(server.addr, "/dir/a").await;
+	assert_eq!(parts.status, 200);
+	assert!(parts.headers.contains_key("etag"));
+}
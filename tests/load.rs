@@ -0,0 +1,36 @@
+//! Slow handlers mustn't hold up anything else the server is doing.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{file, get, root, script, Server};
+
+#[tokio::test]
+async fn slow_handlers_dont_starve_static_files() {
+	let root = root();
+	file(root.path(), "fast.txt", "fast");
+	script(root.path(), "slow", "#!/bin/sh\nsleep 3\necho slow\n");
+	let server = Server::http(root).await;
+
+	// more than there are threads to block
+	let slow = (0..64)
+		.map(|_| tokio::spawn(get(server.addr, "/slow")))
+		.collect::<Vec<_>>();
+	tokio::time::sleep(Duration::from_millis(500)).await;
+
+	let start = Instant::now();
+	for _ in 0..50 {
+		let (parts, body) = get(server.addr, "/fast.txt").await;
+		assert_eq!(parts.status, 200);
+		assert_eq!(body, "fast");
+	}
+	let elapsed = start.elapsed();
+	assert!(elapsed < Duration::from_millis(1500), "static files took {:?}", elapsed);
+
+	for slow in slow {
+		let (parts, body) = slow.await.unwrap();
+		assert_eq!(parts.status, 200);
+		assert_eq!(body, "slow\n");
+	}
+}
@@ -0,0 +1,34 @@
+//! Methods only pick handlers named for them.
+
+mod common;
+
+use common::{file, root, script, send, Server};
+use http_body_util::Full;
+use hyper::Request;
+
+#[tokio::test]
+async fn methods_cannot_name_hooks() {
+	let root = root();
+	let ran = root.path().join("hook-ran");
+	let hook = format!("#!/bin/bash\ntouch {}\necho hook ran\n", ran.display());
+	script(root.path(), "auth/.GET", "#!/bin/bash\necho handler\n");
+	script(root.path(), "auth/.post_process", &hook);
+	script(root.path(), "auth/.pre_process", "#!/bin/bash\ncat\n");
+	file(root.path(), "auth/.error/404", "not found");
+	file(root.path(), "auth/.config", "cache = \"no-store\"\n");
+	let server = Server::http(root).await;
+
+	for method in ["post_process", "pre_process", "index", "error", "config", "get", "Get"] {
+		let req = Request::builder()
+			.method(method)
+			.uri("/auth/")
+			.header("Host", "localhost")
+			.body(Full::default())
+			.unwrap();
+		let (parts, body) = send(server.addr, req).await;
+		assert_eq!(parts.status, 405, "{}", method);
+		assert_eq!(parts.headers["allow"], "GET, HEAD", "{}", method);
+		assert_ne!(body, "hook ran\n", "{}", method);
+		assert!(!server.root.path().join("hook-ran").exists(), "{}", method);
+	}
+}
@@ -0,0 +1,64 @@
+//! `.order` decides between regex directories, and nothing else.
+
+mod common;
+
+use common::{file, get, root, script, Server};
+
+async fn server() -> Server {
+	let root = root();
+	for (dir, name) in [
+		("d/&[a-z]+", "letters"),
+		("d/&new", "new"),
+		("d/&n.*", "n-anything"),
+		("d/plain", "plain"),
+	] {
+		script(root.path(), &format!("{}/.index", dir), &format!("#!/bin/bash\necho {}\n", name));
+	}
+	file(root.path(), "d/.order", "# most specific first\n&new\n.GET\n.index\n&[a-z]+\n");
+	script(root.path(), "d/.GET", "#!/bin/bash\necho method\n");
+	script(root.path(), "d/.index", "#!/bin/bash\necho index\n");
+	Server::http(root).await
+}
+
+async fn body(server: &Server, path: &str) -> (u16, String) {
+	let (parts, body) = get(server.addr, path).await;
+	(parts.status.as_u16(), String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn listed_first_wins() {
+	let server = server().await;
+
+	assert_eq!(body(&server, "/d/new").await, (200, "new\n".to_string()));
+	// listed ahead of the unlisted `&n.*`, even though it sorts after it
+	assert_eq!(body(&server, "/d/nope").await, (200, "letters\n".to_string()));
+	assert_eq!(body(&server, "/d/n1").await, (200, "n-anything\n".to_string()));
+	// plain names don't need ordering
+	assert_eq!(body(&server, "/d/plain").await, (200, "plain\n".to_string()));
+}
+
+#[tokio::test]
+async fn handlers_are_not_ordered() {
+	let server = server().await;
+
+	// the directory itself still goes to its method handler
+	assert_eq!(body(&server, "/d").await, (200, "method\n".to_string()));
+	// and hooks listed in `.order` don't become routes
+	for path in ["/d/.GET", "/d/.index"] {
+		let (status, body) = body(&server, path).await;
+		assert_eq!(status, 403, "{}", path);
+		assert_ne!(body, "method\n", "{}", path);
+	}
+}
+
+#[tokio::test]
+async fn anchored() {
+	let server = server().await;
+
+	assert_eq!(body(&server, "/d/abc").await, (200, "letters\n".to_string()));
+	// neither `&[a-z]+` nor `&new` matches part of a segment
+	for path in ["/d/abc1", "/d/1abc"] {
+		assert_eq!(body(&server, path).await.0, 404, "{}", path);
+	}
+	assert_eq!(body(&server, "/d/newer").await, (200, "letters\n".to_string()));
+}
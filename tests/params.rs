@@ -0,0 +1,68 @@
+//! Captures of regex directories, by position and by name.
+
+mod common;
+
+use common::{get, root, script, Server};
+
+// the named captures it got, and then every argument on a line of its own
+const PRINT: &str = "#!/bin/sh
+echo \"user=$PARAM_user\"
+echo \"id=$PARAM_id\"
+for arg in \"$@\"; do
+	echo \"arg:$arg\"
+done
+";
+
+async fn output(server: &Server, path: &str) -> (Vec<String>, Vec<String>) {
+	let (parts, body) = get(server.addr, path).await;
+	assert_eq!(parts.status, 200);
+	let body = String::from_utf8(body.to_vec()).unwrap();
+	let (args, env): (Vec<&str>, Vec<&str>) = body.lines().partition(|l| l.starts_with("arg:"));
+	// path parameters are everything after the third empty argument, and can
+	// be empty themselves
+	let args = args.iter().map(|a| &a[4..]).collect::<Vec<&str>>();
+	let start = args
+		.iter()
+		.enumerate()
+		.filter(|(_, a)| a.is_empty())
+		.nth(2)
+		.unwrap()
+		.0;
+	let path_params = args[start + 1..].iter().map(|a| a.to_string()).collect();
+	(env.into_iter().map(String::from).collect(), path_params)
+}
+
+#[tokio::test]
+async fn nested_named_groups() {
+	let root = root();
+	script(root.path(), "users/&(?P<user>[a-z]+)/posts/&(?P<id>\\d+)/.index", PRINT);
+	let server = Server::http(root).await;
+
+	let (env, path_params) = output(&server, "/users/bob/posts/42").await;
+	assert_eq!(env, ["user=bob", "id=42"]);
+	// the whole match and then each group, for every regex on the way
+	assert_eq!(path_params, ["bob", "bob", "42", "42"]);
+}
+
+#[tokio::test]
+async fn deeper_groups_shadow_names() {
+	let root = root();
+	script(root.path(), "&(?P<id>[a-z]+)/&(?P<id>\\d+)/.index", PRINT);
+	let server = Server::http(root).await;
+
+	let (env, path_params) = output(&server, "/bob/42").await;
+	assert_eq!(env, ["user=", "id=42"]);
+	assert_eq!(path_params, ["bob", "bob", "42", "42"]);
+}
+
+#[tokio::test]
+async fn unnamed_groups_stay_positional() {
+	let root = root();
+	script(root.path(), "&([a-z]+)-(?P<user>[a-z]+)-(\\d+)/&x(\\d*)/.index", PRINT);
+	let server = Server::http(root).await;
+
+	let (env, path_params) = output(&server, "/ab-cd-12/x").await;
+	assert_eq!(env, ["user=cd", "id="]);
+	// groups that didn't take part are empty, but still there
+	assert_eq!(path_params, ["ab-cd-12", "ab", "cd", "12", "x", ""]);
+}
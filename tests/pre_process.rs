@@ -0,0 +1,82 @@
+//! `.pre_process` runs before anything deeper, and can stop the request,
+//! add parameters, or replace the body.
+
+mod common;
+
+use common::{file, get, root, script, send, Server};
+use http_body_util::Full;
+use hyper::{body::Bytes, Request};
+
+// every argument on a line of its own, and then the body
+const PRINT: &str = "#!/bin/bash\nfor arg in \"$@\"; do\n\techo \"arg:$arg\"\ndone\ncat\n";
+
+async fn post(server: &Server, path: &str, body: &'static str) -> (u16, String) {
+	let req = Request::post(path)
+		.header("Host", "localhost")
+		.body(Full::new(Bytes::from(body)))
+		.unwrap();
+	let (parts, body) = send(server.addr, req).await;
+	(parts.status.as_u16(), String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn short_circuit() {
+	let root = root();
+	let ran = root.path().join("ran");
+	// exit code 27 stands for 403
+	script(root.path(), "locked/.pre_process", "#!/bin/bash\nexit 27\n");
+	script(root.path(), "locked/.index", &format!("#!/bin/bash\ntouch {}\necho handled\n", ran.display()));
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/locked").await;
+	assert_eq!(parts.status, 403);
+	assert_ne!(body, "handled\n");
+	assert!(!server.root.path().join("ran").exists());
+}
+
+#[tokio::test]
+async fn injected_params() {
+	let root = root();
+	script(
+		root.path(),
+		"app/.pre_process",
+		"#!/bin/bash\ncat\necho 'x-user=bob' >&2\necho '?page=2' >&2\necho '/extra' >&2\n",
+	);
+	script(root.path(), "app/.index", PRINT);
+	let server = Server::http(root).await;
+
+	let (status, body) = post(&server, "/app?sort=asc", "body").await;
+	assert_eq!(status, 200);
+	let lines = body.lines().collect::<Vec<&str>>();
+	let header = lines.iter().position(|l| *l == "arg:x-user=bob").expect(&body);
+	let query = lines.iter().position(|l| *l == "arg:page=2").expect(&body);
+	let path = lines.iter().position(|l| *l == "arg:extra").expect(&body);
+	let sort = lines.iter().position(|l| *l == "arg:sort=asc").expect(&body);
+	// each in its own section, after what the request had
+	assert!(header < sort && sort < query && query < path, "{}", body);
+	assert_eq!(lines.last(), Some(&"body"));
+}
+
+#[tokio::test]
+async fn rewritten_body_length() {
+	let root = root();
+	file(root.path(), "cgi/.config", "cgi = true\n");
+	script(root.path(), "cgi/.pre_process", "#!/bin/bash\ncat\necho ' and more'\n");
+	script(
+		root.path(),
+		"cgi/length",
+		"#!/bin/bash\nprintf 'Content-Type: text/plain\\n\\n'\necho \"length:$CONTENT_LENGTH\"\ncat\n",
+	);
+	script(root.path(), "plain/.pre_process", "#!/bin/bash\ncat\necho ' and more'\n");
+	script(root.path(), "plain/.index", PRINT);
+	let server = Server::http(root).await;
+
+	let (status, body) = post(&server, "/cgi/length", "body").await;
+	assert_eq!(status, 200);
+	assert_eq!(body, "length:14\nbody and more\n");
+
+	let (status, body) = post(&server, "/plain", "body").await;
+	assert_eq!(status, 200);
+	assert!(body.lines().any(|l| l == "arg:content-length=14"), "{}", body);
+	assert!(!body.lines().any(|l| l == "arg:content-length=4"), "{}", body);
+}
@@ -0,0 +1,90 @@
+//! Precompressed copies next to a static file are served in its place.
+
+mod common;
+
+use std::{
+	fs::File,
+	path::Path,
+	time::{Duration, SystemTime},
+};
+
+use common::{file, get, root, send, Server};
+use http_body_util::Full;
+use hyper::{body::Bytes, http::response::Parts, Request};
+
+// short enough not to be compressed on the way out
+const ORIGINAL: &str = "console.log('original');\n";
+
+fn age(path: &Path, by: Duration) {
+	File::options()
+		.write(true)
+		.open(path)
+		.unwrap()
+		.set_modified(SystemTime::now() - by)
+		.unwrap();
+}
+
+async fn accepting(server: &Server, path: &str, encodings: &str) -> (Parts, Bytes) {
+	let req = Request::get(path)
+		.header("Host", "localhost")
+		.header("Accept-Encoding", encodings)
+		.body(Full::default())
+		.unwrap();
+	send(server.addr, req).await
+}
+
+async fn server() -> Server {
+	let root = root();
+	file(root.path(), "app.js", ORIGINAL);
+	file(root.path(), "app.js.gz", "gzipped");
+	file(root.path(), "app.js.br", "brotli");
+	file(root.path(), "only-gz.js", ORIGINAL);
+	file(root.path(), "only-gz.js.gz", "gzipped");
+	file(root.path(), "stale.js", ORIGINAL);
+	file(root.path(), "stale.js.gz", "gzipped");
+	age(&root.path().join("app.js"), Duration::from_secs(60));
+	age(&root.path().join("only-gz.js"), Duration::from_secs(60));
+	age(&root.path().join("stale.js.gz"), Duration::from_secs(60));
+	Server::http(root).await
+}
+
+#[tokio::test]
+async fn chosen_by_accept_encoding() {
+	let server = server().await;
+
+	for (accepted, encoding, body) in [
+		("gzip", "gzip", "gzipped"),
+		("br", "br", "brotli"),
+		("gzip, br", "br", "brotli"),
+		("gzip, br;q=0.5", "gzip", "gzipped"),
+	] {
+		let (parts, got) = accepting(&server, "/app.js", accepted).await;
+		assert_eq!(parts.status, 200, "{}", accepted);
+		assert_eq!(parts.headers["content-encoding"], encoding, "{}", accepted);
+		assert_eq!(parts.headers["content-type"], "text/javascript; charset=utf-8", "{}", accepted);
+		assert_eq!(parts.headers["vary"], "Accept-Encoding", "{}", accepted);
+		assert_eq!(got, body, "{}", accepted);
+	}
+	let (parts, got) = accepting(&server, "/app.js", "identity").await;
+	assert!(!parts.headers.contains_key("content-encoding"));
+	assert_eq!(parts.headers["content-type"], "text/javascript; charset=utf-8");
+	assert_eq!(parts.headers["vary"], "Accept-Encoding");
+	assert_eq!(got, ORIGINAL);
+}
+
+#[tokio::test]
+async fn original_without_a_fresh_sibling() {
+	let server = server().await;
+
+	let (parts, got) = accepting(&server, "/only-gz.js", "br").await;
+	assert!(!parts.headers.contains_key("content-encoding"));
+	assert_eq!(got, ORIGINAL);
+	let (parts, got) = accepting(&server, "/stale.js", "gzip").await;
+	assert!(!parts.headers.contains_key("content-encoding"));
+	assert_eq!(parts.headers["content-type"], "text/javascript; charset=utf-8");
+	assert_eq!(got, ORIGINAL);
+	// the copies themselves are still there to ask for
+	let (parts, got) = get(server.addr, "/stale.js.gz").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(got, "gzipped");
+}
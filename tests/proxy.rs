@@ -0,0 +1,120 @@
+//! Proxied responses keep what only the upstream can know.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{file, get, root, script, send, Server};
+use http_body_util::Full;
+use hyper::Request;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+
+// reads up to the end of a header block
+async fn read_head(stream: &mut TcpStream) -> String {
+	let mut head = Vec::new();
+	while !head.ends_with(b"\r\n\r\n") {
+		let mut byte = [0];
+		if stream.read(&mut byte).await.unwrap() == 0 {
+			break;
+		}
+		head.push(byte[0]);
+	}
+	String::from_utf8(head).unwrap()
+}
+
+// answers HEAD with a length, upgrades to an echo of whatever is sent, and
+// anything else with its name
+async fn upstream() -> SocketAddr {
+	let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let addr = listener.local_addr().unwrap();
+	tokio::spawn(async move {
+		loop {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			tokio::spawn(async move {
+				let head = read_head(&mut stream).await;
+				if head.starts_with("HEAD ") {
+					let answer = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 1234\r\n\r\n";
+					stream.write_all(answer.as_bytes()).await.unwrap();
+				} else if head.to_ascii_lowercase().contains("upgrade: echo") {
+					let answer = "HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n";
+					stream.write_all(answer.as_bytes()).await.unwrap();
+					let (mut reader, mut writer) = stream.split();
+					let _ = tokio::io::copy(&mut reader, &mut writer).await;
+				} else {
+					let answer = "HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nupstream";
+					stream.write_all(answer.as_bytes()).await.unwrap();
+				}
+			});
+		}
+	});
+	addr
+}
+
+async fn server() -> Server {
+	let upstream = upstream().await;
+	let root = root();
+	file(root.path(), "api/.proxy", &format!("http://{}\n", upstream));
+	file(root.path(), "ws/.proxy", &format!("http://{}\n", upstream));
+	file(root.path(), "read-only/.proxy", &format!("http://{}\n", upstream));
+	file(root.path(), "read-only/.config", "methods = [\"GET\"]\n");
+	let ran = root.path().join("ws/post-processed");
+	script(root.path(), "ws/.post_process", &format!("#!/bin/bash\ntouch {}\ncat\n", ran.display()));
+	Server::http(root).await
+}
+
+#[tokio::test]
+async fn head_keeps_upstream_length() {
+	let server = server().await;
+
+	let req = Request::head("/api/file")
+		.header("Host", "localhost")
+		.body(Full::default())
+		.unwrap();
+	let (parts, body) = send(server.addr, req).await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(parts.headers["content-length"], "1234");
+	assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn switched_protocol_is_not_post_processed() {
+	let server = server().await;
+
+	let mut stream = TcpStream::connect(server.addr).await.unwrap();
+	let request = "GET /ws/socket HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n";
+	stream.write_all(request.as_bytes()).await.unwrap();
+	let head = read_head(&mut stream).await;
+	assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+	assert!(head.to_ascii_lowercase().contains("upgrade: echo"), "{}", head);
+
+	stream.write_all(b"ping").await.unwrap();
+	let mut echoed = [0; 4];
+	tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut echoed))
+		.await
+		.expect("nothing echoed")
+		.unwrap();
+	assert_eq!(&echoed, b"ping");
+	assert!(!server.root.path().join("ws/post-processed").exists());
+}
+
+#[tokio::test]
+async fn disallowed_methods_are_not_forwarded() {
+	let server = server().await;
+
+	let (parts, body) = get(server.addr, "/read-only/thing").await;
+	assert_eq!(parts.status, 200);
+	assert_eq!(body, "upstream");
+	for path in ["/read-only", "/read-only/thing", "/read-only/deeper/thing"] {
+		let req = Request::delete(path)
+			.header("Host", "localhost")
+			.body(Full::default())
+			.unwrap();
+		let (parts, body) = send(server.addr, req).await;
+		assert_eq!(parts.status, 405, "{}", path);
+		assert_eq!(parts.headers["allow"], "GET, HEAD", "{}", path);
+		assert_ne!(body, "upstream", "{}", path);
+	}
+}
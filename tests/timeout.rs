@@ -0,0 +1,66 @@
+//! Handlers that run too long are killed, along with anything they started.
+
+mod common;
+
+use std::{fs, path::Path, time::Duration};
+
+use common::{file, get, root, script, Server};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+
+// backgrounds a long sleep, so only killing its whole group stops it
+const SLOW: &str = "#!/bin/bash\nsleep 30 &\necho $! > \"$(dirname \"$0\")/pid\"\nwait\n";
+
+// whether the process is gone, or only waiting to be reaped
+async fn gone(pid_file: &Path) -> bool {
+	let pid = fs::read_to_string(pid_file).unwrap();
+	let status = format!("/proc/{}/status", pid.trim());
+	for _ in 0..100 {
+		match fs::read_to_string(&status) {
+			Err(_) => return true,
+			Ok(s) if s.lines().any(|l| l.starts_with("State:") && l.contains("Z")) => return true,
+			Ok(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+		}
+	}
+	false
+}
+
+#[tokio::test]
+async fn timeout_reaches_error_handler() {
+	let root = root();
+	file(root.path(), "slow/.config", "timeout = 1\n");
+	file(root.path(), "slow/.error/504", "custom 504 page");
+	script(root.path(), "slow/.index", SLOW);
+	let server = Server::http(root).await;
+
+	let (parts, body) = get(server.addr, "/slow").await;
+	assert_eq!(parts.status, 504);
+	assert_eq!(body, "custom 504 page");
+	assert!(gone(&server.root.path().join("slow/pid")).await);
+}
+
+#[tokio::test]
+async fn streaming_timeout_kills_group() {
+	let root = root();
+	file(root.path(), "slow/.config", "timeout = 1\ncgi = true\n");
+	script(
+		root.path(),
+		"slow/stream",
+		&SLOW.replacen("\n", "\nprintf 'Content-Type: text/plain\\n\\nstarted\\n'\n", 1),
+	);
+	let server = Server::http(root).await;
+
+	let mut stream = TcpStream::connect(server.addr).await.unwrap();
+	let request = "GET /slow/stream HTTP/1.1\r\nHost: localhost\r\n\r\n";
+	stream.write_all(request.as_bytes()).await.unwrap();
+	let mut response = Vec::new();
+	// cut short once the deadline passes
+	tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+		.await
+		.expect("the response never ended")
+		.unwrap();
+	assert!(String::from_utf8_lossy(&response).contains("started"));
+	assert!(gone(&server.root.path().join("slow/pid")).await);
+}
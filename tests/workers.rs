@@ -0,0 +1,129 @@
+//! Persistent workers are kept between requests, started again, stopped and
+//! killed.
+
+mod common;
+
+use std::{fs, path::Path, time::Duration};
+
+use common::{file, get, root, script, send, Server};
+use http_body_util::Full;
+use hyper::{body::Bytes, Request};
+
+// answers every request with its pid, and fails on a body of `crash`
+const PID: &str = r#"#!/bin/bash
+export LC_ALL=C
+netstring() {
+	local len
+	IFS= read -r -d ':' len || exit 0
+	IFS= read -r -N "$len" data
+	IFS= read -r -N 1 _
+}
+while netstring && netstring && netstring; do
+	[ "$data" = crash ] && exit 1
+	printf '3:200,0:,%d:%s,' "${#$}" "$$"
+done
+"#;
+
+// backgrounds a long sleep and fails before it answers
+const CRASHING: &str = "#!/bin/bash\nsleep 30 >/dev/null 2>&1 &\necho $! > \"$(dirname \"$0\")/pid\"\nexit 1\n";
+
+// whether the process is gone, or only waiting to be reaped
+async fn gone(pid_file: &Path) -> bool {
+	let pid = fs::read_to_string(pid_file).unwrap();
+	let status = format!("/proc/{}/status", pid.trim());
+	for _ in 0..100 {
+		match fs::read_to_string(&status) {
+			Err(_) => return true,
+			Ok(s) if s.lines().any(|l| l.starts_with("State:") && l.contains("Z")) => return true,
+			Ok(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+		}
+	}
+	false
+}
+
+async fn pid(server: &Server, path: &str) -> (u16, String) {
+	let (parts, body) = get(server.addr, path).await;
+	(parts.status.as_u16(), String::from_utf8(body.to_vec()).unwrap())
+}
+
+async fn crash(server: &Server, path: &str) -> u16 {
+	let req = Request::post(path)
+		.header("Host", "localhost")
+		.body(Full::new(Bytes::from("crash")))
+		.unwrap();
+	send(server.addr, req).await.0.status.as_u16()
+}
+
+fn running(pid: &str) -> bool {
+	fs::read_to_string(format!("/proc/{}/status", pid))
+		.is_ok_and(|s| !s.lines().any(|l| l.starts_with("State:") && l.contains("Z")))
+}
+
+#[tokio::test]
+async fn kept_and_restarted() {
+	let root = root();
+	file(root.path(), "w/.config", "[workers]\nsize = 1\n");
+	script(root.path(), "w/.index", PID);
+	let server = Server::http(root).await;
+
+	let (status, first) = pid(&server, "/w").await;
+	assert_eq!(status, 200);
+	// the same one answers again
+	assert_eq!(pid(&server, "/w").await, (200, first.clone()));
+	assert_eq!(crash(&server, "/w").await, 502);
+	let (status, second) = pid(&server, "/w").await;
+	assert_eq!(status, 200);
+	assert_ne!(second, first);
+	assert!(!running(&first));
+}
+
+#[tokio::test]
+async fn idle_workers_are_stopped() {
+	let root = root();
+	file(root.path(), "w/.config", "[workers]\nidle_timeout = 0.2\n");
+	script(root.path(), "w/.index", PID);
+	let server = Server::http(root).await;
+
+	let (status, first) = pid(&server, "/w").await;
+	assert_eq!(status, 200);
+	let mut stopped = false;
+	for _ in 0..100 {
+		if !running(&first) {
+			stopped = true;
+			break;
+		}
+		tokio::time::sleep(Duration::from_millis(20)).await;
+	}
+	assert!(stopped);
+	let (status, second) = pid(&server, "/w").await;
+	assert_eq!(status, 200);
+	assert_ne!(second, first);
+}
+
+#[tokio::test]
+async fn without_restarts() {
+	let root = root();
+	file(root.path(), "w/.config", "[workers]\nsize = 2\nrestart = false\n");
+	script(root.path(), "w/.index", PID);
+	let server = Server::http(root).await;
+
+	assert_eq!(crash(&server, "/w").await, 502);
+	// one left to go on with
+	assert_eq!(pid(&server, "/w").await.0, 200);
+	assert_eq!(crash(&server, "/w").await, 502);
+	// and then none
+	let (status, _) = pid(&server, "/w").await;
+	assert_eq!(status, 503);
+}
+
+#[tokio::test]
+async fn crash_kills_group() {
+	let root = root();
+	file(root.path(), "w/.config", "[workers]\nsize = 1\n");
+	script(root.path(), "w/.index", CRASHING);
+	let server = Server::http(root).await;
+
+	let (parts, _) = get(server.addr, "/w").await;
+	assert_eq!(parts.status, 502);
+	assert!(gone(&server.root.path().join("w/pid")).await);
+}